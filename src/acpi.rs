@@ -0,0 +1,212 @@
+use crate::memory;
+use x86_64::PhysAddr;
+
+/// Maximum number of I/O APICs recorded from the MADT.
+pub const MAX_IO_APICS: usize = 8;
+/// Maximum number of interrupt source overrides recorded from the MADT.
+pub const MAX_OVERRIDES: usize = 16;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const SDT_HEADER_SIZE: u64 = 36;
+
+// the BIOS leaves the RSDP in the first KiB of the EBDA, whose segment is stored
+// at 0x40e, or on a 16 byte boundary in the read-only BIOS area.
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+// types of the MADT entries that are parsed.
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// Tables are read through the physical memory mapping, see `memory::init`.
+    NoPhysicalMemoryMapping,
+    RsdpNotFound,
+    InvalidChecksum([u8; 4]),
+    TableNotFound([u8; 4]),
+}
+
+/// An I/O APIC described by the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt the I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// Describes a legacy ISA IRQ that is not connected to the I/O APIC input with
+/// the same number, or not with the ISA defaults of edge triggered, active high.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn is_active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    pub fn is_level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// The interrupt controller information of the MADT (multiple APIC description table).
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Number of enabled processors.
+    pub processors: usize,
+    io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+impl Madt {
+    pub fn io_apics(&self) -> impl Iterator<Item = &IoApicInfo> {
+        self.io_apics.iter().flatten()
+    }
+
+    pub fn overrides(&self) -> impl Iterator<Item = &InterruptOverride> {
+        self.overrides.iter().flatten()
+    }
+
+    /// Returns the override for the legacy ISA `irq`, if there is one.
+    pub fn interrupt_override(&self, irq: u8) -> Option<&InterruptOverride> {
+        self.overrides().find(|entry| entry.irq == irq)
+    }
+}
+
+// reads physical memory through the mapping of the complete physical memory.
+unsafe fn read_phys<T: Copy>(addr: u64) -> T {
+    let offset = memory::physical_memory_offset().expect("physical memory is not mapped");
+    (offset + addr).as_ptr::<T>().read_unaligned()
+}
+
+unsafe fn checksum_ok(addr: u64, len: u64) -> bool {
+    let sum = (0..len).fold(0u8, |sum, i| sum.wrapping_add(read_phys::<u8>(addr + i)));
+    sum == 0
+}
+
+unsafe fn find_rsdp_in(start: u64, end: u64) -> Option<u64> {
+    (start..end)
+        .step_by(16)
+        .find(|&addr| read_phys::<[u8; 8]>(addr) == *RSDP_SIGNATURE && checksum_ok(addr, 20))
+}
+
+unsafe fn find_rsdp() -> Option<u64> {
+    let ebda = (read_phys::<u16>(EBDA_POINTER) as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = find_rsdp_in(ebda, ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    find_rsdp_in(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+// returns the address of the table with `signature` listed in the RSDT or XSDT.
+unsafe fn find_table(rsdp: u64, signature: &[u8; 4]) -> Result<u64, AcpiError> {
+    let revision: u8 = read_phys(rsdp + 15);
+    // ACPI 2.0 added the XSDT with 64 bit table addresses.
+    let (root, entry_size) = if revision >= 2 {
+        (read_phys::<u64>(rsdp + 24), 8)
+    } else {
+        (read_phys::<u32>(rsdp + 16) as u64, 4)
+    };
+    let root_len: u32 = read_phys(root + 4);
+    if !checksum_ok(root, root_len as u64) {
+        return Err(AcpiError::InvalidChecksum(read_phys(root)));
+    }
+
+    let entries = (root_len as u64).saturating_sub(SDT_HEADER_SIZE) / entry_size;
+    for i in 0..entries {
+        let entry = root + SDT_HEADER_SIZE + i * entry_size;
+        let table = if entry_size == 8 {
+            read_phys::<u64>(entry)
+        } else {
+            read_phys::<u32>(entry) as u64
+        };
+        if read_phys::<[u8; 4]>(table) != *signature {
+            continue;
+        }
+        let len: u32 = read_phys(table + 4);
+        if !checksum_ok(table, len as u64) {
+            return Err(AcpiError::InvalidChecksum(*signature));
+        }
+        return Ok(table);
+    }
+    Err(AcpiError::TableNotFound(*signature))
+}
+
+unsafe fn parse_madt(table: u64) -> Madt {
+    let len = read_phys::<u32>(table + 4) as u64;
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read_phys::<u32>(table + SDT_HEADER_SIZE) as u64),
+        processors: 0,
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+    };
+    let (mut io_apics, mut overrides) = (0, 0);
+
+    // the entries follow the local APIC address and the flags.
+    let mut entry = table + SDT_HEADER_SIZE + 8;
+    while entry + 2 <= table + len {
+        let kind: u8 = read_phys(entry);
+        let entry_len = read_phys::<u8>(entry + 1) as u64;
+        if entry_len < 2 {
+            break;
+        }
+        match kind {
+            MADT_LOCAL_APIC => {
+                let flags: u32 = read_phys(entry + 4);
+                // bit 0 is enabled, bit 1 online capable.
+                if flags & 0b11 != 0 {
+                    madt.processors += 1;
+                }
+            }
+            MADT_IO_APIC if io_apics < MAX_IO_APICS => {
+                madt.io_apics[io_apics] = Some(IoApicInfo {
+                    id: read_phys(entry + 2),
+                    address: PhysAddr::new(read_phys::<u32>(entry + 4) as u64),
+                    gsi_base: read_phys(entry + 8),
+                });
+                io_apics += 1;
+            }
+            MADT_INTERRUPT_OVERRIDE if overrides < MAX_OVERRIDES => {
+                madt.overrides[overrides] = Some(InterruptOverride {
+                    irq: read_phys(entry + 3),
+                    gsi: read_phys(entry + 4),
+                    flags: read_phys(entry + 8),
+                });
+                overrides += 1;
+            }
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic_address = PhysAddr::new(read_phys(entry + 4));
+            }
+            _ => {}
+        }
+        entry += entry_len;
+    }
+    madt
+}
+
+/// Finds the MADT through the RSDP the BIOS left in low memory and parses it.
+///
+/// Needs the physical memory mapping set up by `memory::init`.
+pub fn find_madt() -> Result<Madt, AcpiError> {
+    if memory::physical_memory_offset().is_none() {
+        return Err(AcpiError::NoPhysicalMemoryMapping);
+    }
+    unsafe {
+        let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+        let table = find_table(rsdp, MADT_SIGNATURE)?;
+        Ok(parse_madt(table))
+    }
+}
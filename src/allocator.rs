@@ -1,6 +1,16 @@
+use crate::memory::{self, vmm};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
-use linked_list_allocator::LockedHeap;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use linked_list_allocator::Heap;
+
+pub mod bump;
+pub mod debug;
+pub mod fixed_size_block;
+pub mod oom;
+pub mod stats;
+
+pub use stats::{HeapStats, StatsAllocator};
 
 pub struct Dummy;
 
@@ -16,34 +26,212 @@ unsafe impl GlobalAlloc for Dummy {
     // alloc_zeroed and realloc have their default implementation.
 }
 
+/// A wrapper around spin::Mutex to permit trait implementations.
+// GlobalAlloc can't be implemented for spin::Mutex<A> directly because both
+// the trait and the type are defined in other crates.
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+// the heap behind the global allocator, the fixed_size_block feature selects
+// the fixed size block allocator instead of the plain linked list heap.
+#[cfg(not(feature = "fixed_size_block"))]
+type KernelHeap = GrowableHeap;
+#[cfg(feature = "fixed_size_block")]
+type KernelHeap = fixed_size_block::FixedSizeBlockAllocator;
+
 // assigning a global allocator which provides
 // allocate and deallocate functions.
+// the statistics layer counts every allocation before handing it to the heap,
+// with the heap_debug feature every allocation also gets red zones around it.
+#[cfg(not(feature = "heap_debug"))]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: StatsAllocator<Locked<KernelHeap>> =
+    StatsAllocator::new(Locked::new(KernelHeap::empty()));
+#[cfg(feature = "heap_debug")]
+#[global_allocator]
+static ALLOCATOR: StatsAllocator<debug::RedZoneAllocator<Locked<KernelHeap>>> = StatsAllocator::new(
+    debug::RedZoneAllocator::new(Locked::new(KernelHeap::empty())),
+);
 
+// returns the heap at the bottom of the allocator layers.
+fn heap() -> spin::MutexGuard<'static, KernelHeap> {
+    #[cfg(not(feature = "heap_debug"))]
+    let heap = ALLOCATOR.inner();
+    #[cfg(feature = "heap_debug")]
+    let heap = ALLOCATOR.inner().inner();
+    heap.lock()
+}
+
+// only called after the heap couldn't grow and the shrinkers couldn't free
+// enough memory, see GlobalAlloc for Locked<GrowableHeap>.
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocaion error: {:?}", layout)
+    oom::print_oom_report(layout);
+    crate::hlt_loop();
+}
+
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KB, mapped by init_heap.
+/// Size of the virtual window reserved for the heap, it never grows past its end.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MB.
+
+// the heap grows by at least this many bytes at once to keep the number of growths low.
+const HEAP_GROWTH: usize = 64 * 1024;
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// Sets the size the heap may grow to, limited to `HEAP_MAX_SIZE`.
+///
+/// Memory that is mapped already stays part of the heap.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+/// Returns the size the heap may grow to.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
 }
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KB.
+/// Returns the number of bytes currently mapped for the heap.
+pub fn heap_size() -> usize {
+    heap().size()
+}
+
+/// Returns the current heap statistics.
+pub fn stats() -> HeapStats {
+    let heap = heap();
+    HeapStats {
+        heap_size: heap.size(),
+        heap_used: heap.used(),
+        ..ALLOCATOR.counters()
+    }
+}
 
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTable, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
-// init heap function to initialize heap.
-pub fn init_heap(
+/// A linked list heap that maps more pages behind its end when it runs out of memory.
+///
+/// Growing needs the kernel mapper and frame allocator, so it only works after
+/// `memory::init_kernel_memory` was called.
+pub struct GrowableHeap {
+    heap: Heap,
+}
+
+impl GrowableHeap {
+    pub const fn empty() -> Self {
+        GrowableHeap {
+            heap: Heap::empty(),
+        }
+    }
+
+    /// Initializes the heap with the given mapped memory region.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// region is mapped and unused, and that the memory after it up to the heap
+    /// limit is not used for anything else.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+    }
+
+    /// Allocates memory for the layout, growing the heap if needed.
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        loop {
+            if let Ok(ptr) = self.heap.allocate_first_fit(layout) {
+                return Some(ptr);
+            }
+            // retry the allocation after every successful growth.
+            self.grow(layout)?;
+        }
+    }
+
+    /// Frees memory that was returned by `allocate` with the same layout.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.heap.deallocate(ptr, layout)
+    }
+
+    /// Returns the number of bytes mapped for the heap.
+    pub fn size(&self) -> usize {
+        self.heap.size()
+    }
+
+    /// Returns the number of bytes handed out by the heap.
+    pub fn used(&self) -> usize {
+        self.heap.used()
+    }
+
+    fn grow(&mut self, layout: Layout) -> Option<()> {
+        // before init_heap there is no heap to extend.
+        if self.heap.size() == 0 {
+            return None;
+        }
+        let heap_end = self.heap.bottom() + heap_limit();
+        let top = self.heap.top();
+
+        // the new hole might have to be aligned for the layout.
+        let needed = align_up(layout.size() + layout.align(), 4096);
+        let growth = needed.max(HEAP_GROWTH).min(heap_end.saturating_sub(top));
+        if growth < needed {
+            return None;
+        }
+
+        // the heap is locked right now, so it must not wait for a user of the
+        // kernel memory that might be allocating itself.
+        memory::try_with_kernel_memory(|kernel_memory| {
+            map_heap_pages(
+                top,
+                growth,
+                &mut kernel_memory.mapper,
+                &mut kernel_memory.frame_allocator,
+            )
+        })?
+        .ok()?;
+        unsafe { self.heap.extend(growth) };
+        Some(())
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<GrowableHeap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the heap lock is released before the shrinkers run, because they free memory.
+        oom::alloc_or_reclaim(layout, || self.lock().allocate(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// maps the virtual range [start, start + size) to newly allocated frames.
+fn map_heap_pages(
+    start: usize,
+    size: usize,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
         // containing_address -> returns the page that contains given virtual address.
         let heap_start_page = Page::containing_address(heap_start);
         let heap_page_end = Page::containing_address(heap_end);
@@ -56,7 +244,8 @@ pub fn init_heap(
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?; // if fails gives an early error.
                                                         // ? -> returns error instead of panic.
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let flags = memory::wx::supported_flags(flags);
         // PRESENT -> Specifies whether the mapped frame or page table is loaded in memory.
         // WRITABLE -> Controls whether writes to the mapped frames are allowed.
         // if the bit for this in level 1 page table is unset that means it is read only. If it is unset in
@@ -64,12 +253,27 @@ pub fn init_heap(
 
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
+    Ok(())
+}
+
+// init heap function to initialize heap.
+// The whole heap window of HEAP_MAX_SIZE is reserved in the kernel address space,
+// but only the first HEAP_SIZE bytes are mapped here. The rest is mapped when an
+// allocation needs it.
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let heap_window =
+        vmm::reserve(HEAP_MAX_SIZE as u64).expect("no virtual address space left for the heap");
+    let heap_start = heap_window.start().as_u64() as usize;
+    map_heap_pages(heap_start, HEAP_SIZE, mapper, frame_allocator)?;
     unsafe {
         // using lock() to lock allocator for initializing heap
         // without any interference.
         // and it is unsafe because we are writing to a memory address
         // given by us and not by the code/compiler.
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        heap().init(heap_start, HEAP_SIZE);
     }
     Ok(())
 }
@@ -0,0 +1,184 @@
+use super::{align_up, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+/// A bump allocator that hands out memory from a region by moving a pointer forward.
+///
+/// Single allocations can't be freed, but once every allocation is freed again
+/// the whole region is reused from its start.
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BumpAllocator {
+    /// Creates a new empty bump allocator.
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Initializes the bump allocator with the given heap bounds.
+    ///
+    /// This method is unsafe because the caller must ensure that the given
+    /// memory range is unused. Also, this method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    /// Returns the number of allocations that were not freed yet.
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    /// Returns the number of bytes handed out since the last reset.
+    pub fn used(&self) -> usize {
+        self.next - self.heap_start
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut bump = self.lock(); // get a mutable reference
+
+        let alloc_start = align_up(bump.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+
+        if alloc_end > bump.heap_end {
+            ptr::null_mut() // out of memory
+        } else {
+            bump.next = alloc_end;
+            bump.allocations += 1;
+            alloc_start as *mut u8
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+        let mut bump = self.lock(); // get a mutable reference
+
+        bump.allocations -= 1;
+        // the last allocation was freed, so the whole region is unused again.
+        if bump.allocations == 0 {
+            bump.next = bump.heap_start;
+        }
+    }
+}
+
+/// A typed bump arena over a borrowed buffer.
+///
+/// The arena doesn't need the heap, so it can be used for temporary structures
+/// before `init_heap` runs, e.g. with a buffer on the stack or in a static.
+/// Everything is freed at once by `reset` or by dropping the arena.
+/// Destructors of the allocated values are never run.
+///
+/// ```ignore
+/// let mut buffer = [MaybeUninit::uninit(); 4096];
+/// let arena = Arena::new(&mut buffer);
+/// let regions = arena.alloc_slice_copy(&memory_map[..]).unwrap();
+/// ```
+pub struct Arena<'a> {
+    start: usize,
+    end: usize,
+    next: Cell<usize>,
+    _buffer: PhantomData<&'a mut [MaybeUninit<u8>]>,
+}
+
+impl<'a> Arena<'a> {
+    /// Creates an arena that allocates from the given buffer.
+    pub fn new(buffer: &'a mut [MaybeUninit<u8>]) -> Self {
+        let start = buffer.as_mut_ptr() as usize;
+        Arena {
+            start,
+            end: start + buffer.len(),
+            next: Cell::new(start),
+            _buffer: PhantomData,
+        }
+    }
+
+    /// Moves the value into the arena, gives it back if the arena is full.
+    // every allocation gets its own part of the buffer, so handing out
+    // multiple mutable references from a shared one is fine.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<T>(&self, value: T) -> Result<&mut T, T> {
+        match self.alloc_layout(Layout::new::<T>()) {
+            Some(ptr) => {
+                let ptr = ptr as *mut T;
+                unsafe {
+                    ptr.write(value);
+                    Ok(&mut *ptr)
+                }
+            }
+            None => Err(value),
+        }
+    }
+
+    /// Copies the slice into the arena.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice_copy<T: Copy>(&self, values: &[T]) -> Option<&mut [T]> {
+        let ptr = self.alloc_layout(Layout::for_value(values))? as *mut T;
+        unsafe {
+            ptr::copy_nonoverlapping(values.as_ptr(), ptr, values.len());
+            Some(core::slice::from_raw_parts_mut(ptr, values.len()))
+        }
+    }
+
+    /// Allocates a slice of `len` copies of `value`.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice_fill<T: Copy>(&self, len: usize, value: T) -> Option<&mut [T]> {
+        let layout = Layout::array::<T>(len).ok()?;
+        let ptr = self.alloc_layout(layout)? as *mut T;
+        unsafe {
+            for i in 0..len {
+                ptr.add(i).write(value);
+            }
+            Some(core::slice::from_raw_parts_mut(ptr, len))
+        }
+    }
+
+    /// Frees everything allocated from the arena.
+    ///
+    /// Takes `&mut self`, so no reference into the arena can be alive anymore.
+    pub fn reset(&mut self) {
+        self.next.set(self.start);
+    }
+
+    /// Returns the number of bytes in use, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next.get() - self.start
+    }
+
+    /// Returns the number of bytes that are still free.
+    pub fn remaining(&self) -> usize {
+        self.end - self.next.get()
+    }
+
+    fn alloc_layout(&self, layout: Layout) -> Option<*mut u8> {
+        let alloc_start = align_up(self.next.get(), layout.align());
+        let alloc_end = alloc_start.checked_add(layout.size())?;
+        if alloc_end > self.end {
+            return None;
+        }
+        self.next.set(alloc_end);
+        Some(alloc_start as *mut u8)
+    }
+}
@@ -0,0 +1,99 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+/// Size of the red zones in front of and behind every allocation.
+pub const RED_ZONE_SIZE: usize = 16;
+/// The byte the red zones are filled with.
+pub const CANARY: u8 = 0xab;
+/// The byte freed memory is filled with.
+pub const FREED_POISON: u8 = 0xde;
+/// The byte new allocations are filled with, so reads of uninitialized memory stand out.
+pub const ALLOC_POISON: u8 = 0xcd;
+
+/// A GlobalAlloc wrapper that detects heap corruption.
+///
+/// Every allocation is surrounded by red zones filled with `CANARY`. The red zones
+/// are checked when the allocation is freed and the kernel panics with the layout
+/// and address of the allocation if any of them was overwritten. Freed memory is
+/// filled with `FREED_POISON`, so use after free bugs read an obvious pattern.
+///
+/// The layout of an allocation looks like this, the front red zone is at least as
+/// big as the alignment so the returned pointer stays aligned:
+///
+/// | front red zone | allocation | back red zone |
+pub struct RedZoneAllocator<A> {
+    inner: A,
+}
+
+impl<A> RedZoneAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        RedZoneAllocator { inner }
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+fn front_size(layout: Layout) -> usize {
+    RED_ZONE_SIZE.max(layout.align())
+}
+
+fn outer_layout(layout: Layout) -> Option<Layout> {
+    let size = front_size(layout)
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE_SIZE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+// returns the first offset in the zone that doesn't hold the canary.
+unsafe fn find_corruption(zone: *const u8, len: usize) -> Option<usize> {
+    (0..len).find(|&i| *zone.add(i) != CANARY)
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for RedZoneAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let outer = match outer_layout(layout) {
+            Some(outer) => outer,
+            None => return ptr::null_mut(),
+        };
+        let start = self.inner.alloc(outer);
+        if start.is_null() {
+            return start;
+        }
+
+        let front = front_size(layout);
+        let ptr = start.add(front);
+        ptr::write_bytes(start, CANARY, front);
+        ptr::write_bytes(ptr, ALLOC_POISON, layout.size());
+        ptr::write_bytes(ptr.add(layout.size()), CANARY, RED_ZONE_SIZE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let front = front_size(layout);
+        let start = ptr.sub(front);
+
+        if let Some(offset) = find_corruption(start, front) {
+            panic!(
+                "heap corruption: red zone before {:?} at {:p} overwritten at offset -{}",
+                layout,
+                ptr,
+                front - offset
+            );
+        }
+        if let Some(offset) = find_corruption(ptr.add(layout.size()), RED_ZONE_SIZE) {
+            panic!(
+                "heap corruption: red zone after {:?} at {:p} overwritten at offset +{}",
+                layout,
+                ptr,
+                layout.size() + offset
+            );
+        }
+
+        let outer = outer_layout(layout).unwrap();
+        ptr::write_bytes(start, FREED_POISON, outer.size());
+        self.inner.dealloc(start, outer)
+    }
+}
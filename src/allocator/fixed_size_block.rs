@@ -0,0 +1,141 @@
+use super::{oom, GrowableHeap, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr::NonNull};
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the block alignment (alignments must be always powers of 2).
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// a free block, the node is stored inside the block itself.
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// An allocator that serves small allocations from per size class free lists.
+///
+/// Every allocation is rounded up to the next block size, so allocating and freeing
+/// is a push or pop on a list. Allocations bigger than the largest block size, and
+/// new blocks for empty lists, come from the growable linked list heap.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: GrowableHeap,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty FixedSizeBlockAllocator.
+    pub const fn empty() -> Self {
+        // Option<&mut ListNode> is not Copy, so the array can't be built with [None; N].
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: GrowableHeap::empty(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Returns the number of bytes mapped for the heap.
+    pub fn size(&self) -> usize {
+        self.fallback_allocator.size()
+    }
+
+    /// Returns the number of bytes taken from the fallback heap, this includes
+    /// blocks that sit on a free list.
+    pub fn used(&self) -> usize {
+        self.fallback_allocator.used()
+    }
+
+    /// Gives all blocks on the free lists back to the fallback allocator and
+    /// returns the number of bytes freed.
+    pub fn drop_caches(&mut self) -> usize {
+        let mut freed = 0;
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            let layout = Layout::from_size_align(block_size, block_size).unwrap();
+            while let Some(node) = self.list_heads[index].take() {
+                self.list_heads[index] = node.next.take();
+                let ptr = NonNull::from(node).cast::<u8>();
+                unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                freed += block_size;
+            }
+        }
+        freed
+    }
+
+    /// Allocates using the fallback allocator.
+    fn fallback_alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        if let Some(ptr) = self.fallback_allocator.allocate(layout) {
+            return Some(ptr);
+        }
+        // the free lists might hold enough memory for the allocation.
+        if self.drop_caches() == 0 {
+            return None;
+        }
+        self.fallback_allocator.allocate(layout)
+    }
+
+    /// Allocates a block for the layout.
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    Some(NonNull::from(node).cast())
+                }
+                None => {
+                    // no block exists in list => allocate new block
+                    let block_size = BLOCK_SIZES[index];
+                    // only works if all block sizes are a power of 2
+                    let block_align = block_size;
+                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                    self.fallback_alloc(layout)
+                }
+            },
+            None => self.fallback_alloc(layout),
+        }
+    }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the lock is released before the shrinkers run, because they free memory.
+        oom::alloc_or_reclaim(layout, || self.lock().allocate(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: allocator.list_heads[index].take(),
+                };
+                // verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.fallback_allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use alloc::alloc::Layout;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// A callback that frees memory when the heap runs out of it.
+///
+/// It gets the layout of the failed allocation and returns the number of bytes it
+/// freed. Shrinkers may free heap memory but should not allocate.
+pub type Shrinker = fn(Layout) -> usize;
+
+const MAX_SHRINKERS: usize = 16;
+
+static SHRINKERS: Mutex<[Option<(&'static str, Shrinker)>; MAX_SHRINKERS]> =
+    Mutex::new([None; MAX_SHRINKERS]);
+
+// set while shrinkers run, so allocations made by them don't reclaim again.
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// Identifies a registered shrinker, see `unregister_shrinker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShrinkerHandle(usize);
+
+/// Registers a callback that is run when an allocation fails.
+///
+/// Returns `None` if all shrinker slots are taken.
+pub fn register_shrinker(name: &'static str, shrinker: Shrinker) -> Option<ShrinkerHandle> {
+    let mut shrinkers = SHRINKERS.lock();
+    let index = shrinkers.iter().position(|s| s.is_none())?;
+    shrinkers[index] = Some((name, shrinker));
+    Some(ShrinkerHandle(index))
+}
+
+/// Removes a shrinker registered with `register_shrinker`.
+pub fn unregister_shrinker(handle: ShrinkerHandle) {
+    SHRINKERS.lock()[handle.0] = None;
+}
+
+/// Runs all registered shrinkers and returns the number of bytes they freed.
+pub fn reclaim(layout: Layout) -> usize {
+    if RECLAIMING.swap(true, Ordering::SeqCst) {
+        return 0;
+    }
+    // the lock isn't held while the shrinkers run, they may free memory and
+    // that must not wait for the shrinker table.
+    let shrinkers = *SHRINKERS.lock();
+    let freed = shrinkers
+        .iter()
+        .flatten()
+        .map(|(_, shrinker)| shrinker(layout))
+        .sum();
+    RECLAIMING.store(false, Ordering::SeqCst);
+    freed
+}
+
+// a shrinker that keeps reporting freed bytes without helping the allocation
+// must not make it retry forever.
+const MAX_RECLAIM_ROUNDS: usize = MAX_SHRINKERS;
+
+/// Calls `allocate` and, as long as it fails, runs the shrinkers and retries
+/// until they can't free anything anymore or `MAX_RECLAIM_ROUNDS` rounds ran.
+pub(super) fn alloc_or_reclaim<F>(layout: Layout, mut allocate: F) -> *mut u8
+where
+    F: FnMut() -> Option<NonNull<u8>>,
+{
+    for _ in 0..MAX_RECLAIM_ROUNDS {
+        if let Some(ptr) = allocate() {
+            return ptr.as_ptr();
+        }
+        if reclaim(layout) == 0 {
+            return null_mut();
+        }
+    }
+    allocate().map_or(null_mut(), NonNull::as_ptr)
+}
+
+/// Prints everything known about the heap when an allocation could not be satisfied.
+pub fn print_oom_report(layout: Layout) {
+    use crate::serial_println;
+
+    let stats = super::stats();
+    serial_println!("OUT OF MEMORY: failed to allocate {:?}", layout);
+    serial_println!(
+        "heap: {} bytes mapped, {} bytes used, limit {} bytes",
+        stats.heap_size,
+        stats.heap_used,
+        super::heap_limit()
+    );
+    serial_println!(
+        "allocations: {} bytes in use (peak {}), {} live, {} failed",
+        stats.bytes_in_use,
+        stats.peak_bytes_in_use,
+        stats.live_allocations(),
+        stats.failed_allocations
+    );
+    serial_println!("fragmentation: {} bytes", stats.fragmentation());
+    if let Some(free_frames) =
+        crate::memory::try_with_kernel_memory(|m| m.frame_allocator.free_frames())
+    {
+        serial_println!("physical memory: {} frames free", free_frames);
+    }
+    for (name, _) in SHRINKERS.lock().iter().flatten() {
+        serial_println!("shrinker: {}", name);
+    }
+}
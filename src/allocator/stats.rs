@@ -0,0 +1,225 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// A GlobalAlloc wrapper that counts what goes through the inner allocator.
+pub struct StatsAllocator<A> {
+    inner: A,
+    bytes_in_use: AtomicUsize,
+    peak_bytes_in_use: AtomicUsize,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    failed_allocations: AtomicUsize,
+}
+
+/// A snapshot of the heap counters, see `allocator::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes requested by allocations that were not freed yet.
+    pub bytes_in_use: usize,
+    /// The highest value `bytes_in_use` ever had.
+    pub peak_bytes_in_use: usize,
+    /// Number of successful allocations since boot.
+    pub allocations: usize,
+    /// Number of deallocations since boot.
+    pub deallocations: usize,
+    /// Number of allocations that returned null.
+    pub failed_allocations: usize,
+    /// Bytes mapped for the heap.
+    pub heap_size: usize,
+    /// Bytes the heap has handed out, including padding and cached blocks.
+    pub heap_used: usize,
+}
+
+impl HeapStats {
+    /// Returns the number of allocations that were not freed yet.
+    pub fn live_allocations(&self) -> usize {
+        self.allocations - self.deallocations
+    }
+
+    /// Returns the bytes the heap uses beyond what was requested, i.e. alignment
+    /// padding, rounding up to block sizes and blocks waiting on free lists.
+    pub fn fragmentation(&self) -> usize {
+        self.heap_used.saturating_sub(self.bytes_in_use)
+    }
+}
+
+impl<A> StatsAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        StatsAllocator {
+            inner,
+            bytes_in_use: AtomicUsize::new(0),
+            peak_bytes_in_use: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            failed_allocations: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Returns the counters, the heap size fields have to be filled by the caller.
+    pub fn counters(&self) -> HeapStats {
+        HeapStats {
+            bytes_in_use: self.bytes_in_use.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak_bytes_in_use.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            failed_allocations: self.failed_allocations.load(Ordering::Relaxed),
+            heap_size: 0,
+            heap_used: 0,
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for StatsAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            self.failed_allocations.fetch_add(1, Ordering::Relaxed);
+            return ptr;
+        }
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        let in_use = self
+            .bytes_in_use
+            .fetch_add(layout.size(), Ordering::Relaxed)
+            + layout.size();
+        self.peak_bytes_in_use.fetch_max(in_use, Ordering::Relaxed);
+        track_alloc(ptr, layout);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        track_dealloc(ptr);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in_use
+            .fetch_sub(layout.size(), Ordering::Relaxed);
+        self.inner.dealloc(ptr, layout)
+    }
+
+    // alloc_zeroed and realloc have their default implementation, which goes
+    // through alloc and dealloc above.
+}
+
+// Recording live allocations.
+// The table can't live on the heap because it is updated from inside the
+// allocator, so it has a fixed size.
+
+const MAX_TRACKED: usize = 1024;
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static TRACKED: Mutex<TrackedAllocations> = Mutex::new(TrackedAllocations {
+    entries: [None; MAX_TRACKED],
+    dropped: 0,
+});
+
+struct TrackedAllocations {
+    entries: [Option<(usize, Layout)>; MAX_TRACKED],
+    // allocations that didn't fit into the table.
+    dropped: usize,
+}
+
+/// Starts or stops recording every live allocation with its layout.
+///
+/// Starting clears the previous records, allocations made while recording was
+/// off are never reported.
+pub fn track_allocations(enable: bool) {
+    if enable {
+        without_interrupts(|| {
+            let mut tracked = TRACKED.lock();
+            tracked.entries = [None; MAX_TRACKED];
+            tracked.dropped = 0;
+        });
+    }
+    TRACKING.store(enable, Ordering::SeqCst);
+}
+
+/// Calls `f` with the address and layout of every recorded live allocation.
+///
+/// `f` must not allocate, it runs with interrupts disabled.
+pub fn for_each_live_allocation<F: FnMut(usize, Layout)>(mut f: F) {
+    without_interrupts(|| {
+        let tracked = TRACKED.lock();
+        for &(addr, layout) in tracked.entries.iter().flatten() {
+            f(addr, layout);
+        }
+    });
+}
+
+fn track_alloc(ptr: *mut u8, layout: Layout) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    // interrupt handlers allocate too, they must not find the lock taken.
+    without_interrupts(|| {
+        let mut tracked = TRACKED.lock();
+        match tracked.entries.iter_mut().find(|e| e.is_none()) {
+            Some(entry) => *entry = Some((ptr as usize, layout)),
+            None => tracked.dropped += 1,
+        }
+    });
+}
+
+fn track_dealloc(ptr: *mut u8) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    without_interrupts(|| {
+        let mut tracked = TRACKED.lock();
+        let entry = tracked
+            .entries
+            .iter_mut()
+            .find(|e| matches!(e, Some((addr, _)) if *addr == ptr as usize));
+        if let Some(entry) = entry {
+            *entry = None;
+        }
+    });
+}
+
+/// The allocations a piece of code left behind, see `leak_check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeakReport {
+    pub leaked_allocations: usize,
+    pub leaked_bytes: usize,
+    /// Allocations that were made but didn't fit into the tracking table.
+    pub untracked_allocations: usize,
+}
+
+/// Runs `f` while recording allocations and reports the ones still alive afterwards.
+///
+/// Recording is global, so allocations made by interrupt handlers in the meantime
+/// are reported as well. Calls can't be nested.
+pub fn leak_check<F: FnOnce()>(f: F) -> LeakReport {
+    track_allocations(true);
+    f();
+    track_allocations(false);
+
+    let mut report = LeakReport {
+        leaked_allocations: 0,
+        leaked_bytes: 0,
+        untracked_allocations: without_interrupts(|| TRACKED.lock().dropped),
+    };
+    for_each_live_allocation(|_, layout| {
+        report.leaked_allocations += 1;
+        report.leaked_bytes += layout.size();
+    });
+    report
+}
+
+/// Runs `f` and panics if it leaves any allocation behind.
+pub fn assert_no_leaks<F: FnOnce()>(f: F) {
+    let report = leak_check(f);
+    if report.leaked_allocations != 0 {
+        for_each_live_allocation(|addr, layout| {
+            crate::serial_println!("leaked {:?} at {:#x}", layout, addr);
+        });
+        panic!(
+            "{} allocations leaked {} bytes",
+            report.leaked_allocations, report.leaked_bytes
+        );
+    }
+}
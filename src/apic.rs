@@ -0,0 +1,425 @@
+use crate::acpi::{self, AcpiError, IoApicInfo, Madt, MAX_IO_APICS};
+use crate::memory::cpuid;
+use crate::memory::mmio::{self, CacheMode, MmioRegion};
+use crate::memory::vmm::VmmError;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+/// The vector the local APIC raises for spurious interrupts, which must not be
+/// acknowledged with an EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// registers of the local APIC, they are 16 byte aligned and 32 bits wide.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LAPIC_SIZE: usize = 0x400;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+// the I/O APIC is programmed indirectly: the register number is written to
+// IOREGSEL and the register is then accessed through IOWIN.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_SIZE: usize = 0x20;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DESTINATION_SHIFT: u64 = 56;
+
+#[derive(Debug)]
+pub enum ApicError {
+    NotSupported,
+    AlreadyInitialized,
+    NotInitialized,
+    Acpi(AcpiError),
+    NoIoApic,
+    /// No I/O APIC handles the global system interrupt.
+    NoRoute(u32),
+    Map(VmmError),
+}
+
+impl From<AcpiError> for ApicError {
+    fn from(err: AcpiError) -> Self {
+        ApicError::Acpi(err)
+    }
+}
+
+impl From<VmmError> for ApicError {
+    fn from(err: VmmError) -> Self {
+        ApicError::Map(err)
+    }
+}
+
+/// How the device drives an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqMode {
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl IrqMode {
+    /// ISA devices raise edge triggered, active high interrupts.
+    pub const ISA: IrqMode = IrqMode {
+        active_low: false,
+        level_triggered: false,
+    };
+    /// PCI devices share level triggered, active low lines.
+    pub const PCI: IrqMode = IrqMode {
+        active_low: true,
+        level_triggered: true,
+    };
+}
+
+/// The local APIC of the processor, it receives the interrupts routed to the
+/// processor and must be told when one was handled.
+pub struct LocalApic {
+    regs: MmioRegion,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, reg: usize) -> u32 {
+        self.regs.read(reg)
+    }
+
+    unsafe fn write(&self, reg: usize, value: u32) {
+        self.regs.write(reg, value)
+    }
+
+    pub fn id(&self) -> u8 {
+        (unsafe { self.read(LAPIC_ID) } >> 24) as u8
+    }
+
+    /// Signals the end of the interrupt that is being handled.
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(LAPIC_EOI, 0) };
+    }
+
+    unsafe fn enable(&self) {
+        // the local timer is not used and LINT0 is where the 8259 PIC is
+        // wired in virtual wire mode, LINT1 carries NMIs.
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        self.write(LAPIC_LVT_LINT0, LVT_MASKED);
+        self.write(LAPIC_LVT_LINT1, LVT_DELIVERY_NMI);
+        self.write(LAPIC_LVT_ERROR, LVT_MASKED);
+        // accept interrupts of every priority.
+        self.write(LAPIC_TASK_PRIORITY, 0);
+        self.write(
+            LAPIC_SPURIOUS,
+            SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+    }
+}
+
+/// An I/O APIC, it forwards the interrupt lines of devices to local APICs.
+pub struct IoApic {
+    info: IoApicInfo,
+    redirection_entries: u32,
+    // IOREGSEL and IOWIN have to be accessed together.
+    regs: Mutex<MmioRegion>,
+}
+
+impl IoApic {
+    unsafe fn new(info: IoApicInfo) -> Result<Self, ApicError> {
+        let regs = mmio::ioremap(info.address, IOAPIC_SIZE, CacheMode::Uncached)?;
+        let mut io_apic = IoApic {
+            info,
+            redirection_entries: 0,
+            regs: Mutex::new(regs),
+        };
+        io_apic.redirection_entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        Ok(io_apic)
+    }
+
+    pub fn info(&self) -> IoApicInfo {
+        self.info
+    }
+
+    /// Returns the number of interrupt inputs.
+    pub fn redirection_entries(&self) -> u32 {
+        self.redirection_entries
+    }
+
+    /// Returns whether the global system interrupt is one of this I/O APIC's inputs.
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.info.gsi_base && gsi - self.info.gsi_base < self.redirection_entries
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        without_interrupts(|| {
+            let regs = self.regs.lock();
+            regs.write(IOREGSEL, reg);
+            regs.read(IOWIN)
+        })
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        without_interrupts(|| {
+            let regs = self.regs.lock();
+            regs.write(IOREGSEL, reg);
+            regs.write(IOWIN, value);
+        })
+    }
+
+    fn redirection(&self, gsi: u32) -> u64 {
+        let reg = IOAPIC_REDIRECTION_TABLE + (gsi - self.info.gsi_base) * 2;
+        unsafe { u64::from(self.read(reg)) | u64::from(self.read(reg + 1)) << 32 }
+    }
+
+    unsafe fn set_redirection(&self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION_TABLE + (gsi - self.info.gsi_base) * 2;
+        // masked while the two halves do not match.
+        self.write(reg, REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+/// The local APIC and the I/O APICs of the MADT.
+pub struct Apic {
+    local: LocalApic,
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    madt: Madt,
+}
+
+impl Apic {
+    fn io_apic_for(&self, gsi: u32) -> Result<&IoApic, ApicError> {
+        self.io_apics
+            .iter()
+            .flatten()
+            .find(|io_apic| io_apic.handles(gsi))
+            .ok_or(ApicError::NoRoute(gsi))
+    }
+
+    // masks every input of every I/O APIC.
+    unsafe fn mask_all(&self) {
+        for io_apic in self.io_apics.iter().flatten() {
+            for input in 0..io_apic.redirection_entries {
+                io_apic.set_redirection(io_apic.info.gsi_base + input, REDIRECTION_MASKED);
+            }
+        }
+    }
+
+    pub fn local_apic(&self) -> &LocalApic {
+        &self.local
+    }
+
+    pub fn madt(&self) -> &Madt {
+        &self.madt
+    }
+
+    /// Returns the global system interrupt and mode of the legacy ISA `irq`.
+    pub fn isa_irq_to_gsi(&self, irq: u8) -> (u32, IrqMode) {
+        match self.madt.interrupt_override(irq) {
+            Some(entry) => (
+                entry.gsi,
+                IrqMode {
+                    active_low: entry.is_active_low(),
+                    level_triggered: entry.is_level_triggered(),
+                },
+            ),
+            None => (u32::from(irq), IrqMode::ISA),
+        }
+    }
+
+    /// Delivers the global system interrupt `gsi` to `vector` on this processor and unmasks it.
+    pub fn route_gsi(&self, gsi: u32, vector: u8, mode: IrqMode) -> Result<(), ApicError> {
+        let io_apic = self.io_apic_for(gsi)?;
+
+        let mut entry = u64::from(vector);
+        if mode.active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if mode.level_triggered {
+            entry |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        entry |= u64::from(self.local.id()) << REDIRECTION_DESTINATION_SHIFT;
+        unsafe { io_apic.set_redirection(gsi, entry) };
+        Ok(())
+    }
+
+    /// Delivers the legacy ISA `irq` to `vector`, following the MADT's overrides.
+    pub fn route_irq(&self, irq: u8, vector: u8) -> Result<(), ApicError> {
+        let (gsi, mode) = self.isa_irq_to_gsi(irq);
+        self.route_gsi(gsi, vector, mode)
+    }
+
+    /// Masks or unmasks a global system interrupt without changing its route.
+    pub fn set_gsi_masked(&self, gsi: u32, masked: bool) -> Result<(), ApicError> {
+        let io_apic = self.io_apic_for(gsi)?;
+        without_interrupts(|| {
+            let entry = io_apic.redirection(gsi);
+            let entry = if masked {
+                entry | REDIRECTION_MASKED
+            } else {
+                entry & !REDIRECTION_MASKED
+            };
+            unsafe { io_apic.set_redirection(gsi, entry) };
+        });
+        Ok(())
+    }
+
+    /// Returns whether the global system interrupt is masked.
+    pub fn is_gsi_masked(&self, gsi: u32) -> Result<bool, ApicError> {
+        let io_apic = self.io_apic_for(gsi)?;
+        Ok(io_apic.redirection(gsi) & REDIRECTION_MASKED != 0)
+    }
+
+    /// Returns the vector the global system interrupt is delivered to.
+    pub fn gsi_vector(&self, gsi: u32) -> Result<u8, ApicError> {
+        let io_apic = self.io_apic_for(gsi)?;
+        Ok(io_apic.redirection(gsi) as u8)
+    }
+}
+
+static APIC: Once<Apic> = Once::new();
+
+/// Returns whether the CPU has a local APIC.
+pub fn is_supported() -> bool {
+    cpuid(1).edx & (1 << 9) != 0
+}
+
+/// Sets up the local APIC and the I/O APICs listed in the MADT.
+///
+/// Every I/O APIC input is masked and `route` is called to route the lines that
+/// are in use. Only when it succeeds the local APIC is enabled and the APIC
+/// published, otherwise the inputs are masked again and the error is returned.
+/// Needs the kernel memory from `memory::init_kernel_memory` to map the registers.
+/// The 8259 PIC has to be masked by the caller after a success.
+///
+/// This function is unsafe because the caller must make sure that no interrupt
+/// of the PIC is being handled.
+pub unsafe fn init(route: impl FnOnce(&Apic) -> Result<(), ApicError>) -> Result<(), ApicError> {
+    if APIC.r#try().is_some() {
+        return Err(ApicError::AlreadyInitialized);
+    }
+    if !is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::find_madt()?;
+    if madt.io_apics().next().is_none() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let mut base = Msr::new(IA32_APIC_BASE);
+    let value = base.read();
+    // the MADT address is preferred, it reflects the address override entry.
+    let address = if madt.local_apic_address.is_null() {
+        PhysAddr::new(value & APIC_BASE_ADDRESS_MASK)
+    } else {
+        madt.local_apic_address
+    };
+    let local = LocalApic {
+        regs: mmio::ioremap(address, LAPIC_SIZE, CacheMode::Uncached)?,
+    };
+
+    const NONE: Option<IoApic> = None;
+    let mut io_apics = [NONE; MAX_IO_APICS];
+    for (slot, info) in io_apics.iter_mut().zip(madt.io_apics()) {
+        *slot = Some(IoApic::new(*info)?);
+    }
+    let apic = Apic {
+        local,
+        io_apics,
+        madt,
+    };
+    apic.mask_all();
+    // the ID register is read while routing. The local APIC only stops
+    // forwarding the PIC's interrupts once `enable` masks LINT0.
+    base.write(value | APIC_BASE_ENABLE);
+    if let Err(err) = route(&apic) {
+        // the registers are unmapped when `apic` is dropped.
+        apic.mask_all();
+        base.write(value);
+        return Err(err);
+    }
+
+    apic.local.enable();
+    APIC.call_once(|| apic);
+    Ok(())
+}
+
+/// Returns the APIC once `init` succeeded.
+pub fn get() -> Option<&'static Apic> {
+    APIC.r#try()
+}
+
+fn initialized() -> Result<&'static Apic, ApicError> {
+    get().ok_or(ApicError::NotInitialized)
+}
+
+/// Returns whether interrupts are delivered through the APIC.
+pub fn is_enabled() -> bool {
+    get().is_some()
+}
+
+pub fn local_apic() -> Option<&'static LocalApic> {
+    get().map(Apic::local_apic)
+}
+
+/// Returns the MADT the APIC was set up from.
+pub fn madt() -> Option<&'static Madt> {
+    get().map(Apic::madt)
+}
+
+/// Returns the I/O APICs that were set up.
+pub fn io_apics() -> impl Iterator<Item = &'static IoApic> {
+    get()
+        .into_iter()
+        .flat_map(|apic| apic.io_apics.iter().flatten())
+}
+
+/// Signals the end of an interrupt to the local APIC.
+///
+/// Does nothing before `init`.
+pub fn end_of_interrupt() {
+    if let Some(local) = local_apic() {
+        local.end_of_interrupt();
+    }
+}
+
+/// See `Apic::isa_irq_to_gsi`.
+pub fn isa_irq_to_gsi(irq: u8) -> Result<(u32, IrqMode), ApicError> {
+    Ok(initialized()?.isa_irq_to_gsi(irq))
+}
+
+/// See `Apic::route_gsi`.
+pub fn route_gsi(gsi: u32, vector: u8, mode: IrqMode) -> Result<(), ApicError> {
+    initialized()?.route_gsi(gsi, vector, mode)
+}
+
+/// See `Apic::route_irq`.
+pub fn route_irq(irq: u8, vector: u8) -> Result<(), ApicError> {
+    initialized()?.route_irq(irq, vector)
+}
+
+/// See `Apic::set_gsi_masked`.
+pub fn set_gsi_masked(gsi: u32, masked: bool) -> Result<(), ApicError> {
+    initialized()?.set_gsi_masked(gsi, masked)
+}
+
+/// See `Apic::is_gsi_masked`.
+pub fn is_gsi_masked(gsi: u32) -> Result<bool, ApicError> {
+    initialized()?.is_gsi_masked(gsi)
+}
+
+/// See `Apic::gsi_vector`.
+pub fn gsi_vector(gsi: u32) -> Result<u8, ApicError> {
+    initialized()?.gsi_vector(gsi)
+}
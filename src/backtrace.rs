@@ -0,0 +1,123 @@
+use crate::memory;
+use crate::serial_println;
+use crate::symbols::Symbolized;
+use crate::trap::TrapFrame;
+use core::arch::asm;
+use x86_64::VirtAddr;
+
+// guards against loops in a corrupted chain of frame pointers.
+const MAX_FRAMES: usize = 64;
+
+/// A stack frame found by following the saved frame pointers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_pointer: VirtAddr,
+    pub return_address: VirtAddr,
+}
+
+/// Walks the chain of frame pointers the kernel is built with.
+///
+/// Every frame starts with the caller's frame pointer followed by the return
+/// address. The walk stops at a null or misaligned frame pointer, at memory
+/// that is not mapped and when the frame pointers stop growing.
+pub struct FrameWalker {
+    frame_pointer: u64,
+    depth: usize,
+}
+
+impl FrameWalker {
+    /// Starts the walk at the frame `frame_pointer` points to.
+    pub fn new(frame_pointer: u64) -> Self {
+        FrameWalker {
+            frame_pointer,
+            depth: 0,
+        }
+    }
+}
+
+impl Iterator for FrameWalker {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        let frame_pointer = self.frame_pointer;
+        if frame_pointer == 0 || !frame_pointer.is_multiple_of(8) || self.depth >= MAX_FRAMES {
+            return None;
+        }
+        let frame_addr = VirtAddr::try_new(frame_pointer).ok()?;
+        if !is_mapped(frame_addr) || !is_mapped(frame_addr + 8u64) {
+            return None;
+        }
+        let (saved_frame_pointer, return_address) = unsafe {
+            let ptr: *const u64 = frame_addr.as_ptr();
+            (ptr.read_volatile(), ptr.add(1).read_volatile())
+        };
+        let return_address = VirtAddr::try_new(return_address).ok()?;
+        if return_address.as_u64() == 0 {
+            return None;
+        }
+
+        self.depth += 1;
+        // the stack grows down, so the callers' frames are at higher addresses.
+        self.frame_pointer = if saved_frame_pointer > frame_pointer {
+            saved_frame_pointer
+        } else {
+            0
+        };
+        Some(Frame {
+            frame_pointer: frame_addr,
+            return_address,
+        })
+    }
+}
+
+// without the physical memory offset nothing can be checked, so nothing is read.
+fn is_mapped(addr: VirtAddr) -> bool {
+    match memory::physical_memory_offset() {
+        Some(offset) => unsafe { memory::translate(addr, offset).is_some() },
+        None => false,
+    }
+}
+
+/// Returns the frame pointer of the calling function.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// Returns a walker over the frames of the calling function's callers.
+#[inline(always)]
+pub fn walk() -> FrameWalker {
+    FrameWalker::new(frame_pointer())
+}
+
+/// Prints the return addresses of the current call stack to serial.
+#[inline(never)]
+pub fn print_backtrace() {
+    serial_println!("backtrace:");
+    print_frames(walk(), 0);
+}
+
+/// Prints the call stack of the code an exception interrupted to serial, using
+/// the frame pointer saved by the exception entry stub.
+pub fn print_trap_backtrace(frame: &TrapFrame) {
+    serial_println!("backtrace of the interrupted code:");
+    serial_println!("  #0  {}", Symbolized::new(frame.instruction_pointer()));
+    print_frames(FrameWalker::new(frame.rbp), 1);
+}
+
+fn print_frames(frames: FrameWalker, first: usize) {
+    let mut count = 0;
+    for (index, frame) in frames.enumerate() {
+        serial_println!(
+            "  #{:<2} {}",
+            first + index,
+            Symbolized::return_address(frame.return_address)
+        );
+        count += 1;
+    }
+    if count == 0 && memory::physical_memory_offset().is_none() {
+        serial_println!("  (unavailable before memory::init)");
+    }
+}
@@ -0,0 +1,352 @@
+use crate::interrupts;
+use crate::memory;
+use crate::serial_println;
+use crate::symbols::Symbolized;
+use crate::trap::TrapFrame;
+use core::fmt;
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::VirtAddr;
+
+/// Number of instruction bytes printed at the faulting instruction pointer,
+/// the longest x86 instruction has 15.
+pub const INSTRUCTION_BYTES: usize = 16;
+
+/// The architectural exceptions, the value is the vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    SecurityException = 30,
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_vector(vector: u8) -> Option<Exception> {
+        let exceptions = [
+            Exception::DivideError,
+            Exception::Debug,
+            Exception::NonMaskableInterrupt,
+            Exception::Breakpoint,
+            Exception::Overflow,
+            Exception::BoundRangeExceeded,
+            Exception::InvalidOpcode,
+            Exception::DeviceNotAvailable,
+            Exception::DoubleFault,
+            Exception::InvalidTss,
+            Exception::SegmentNotPresent,
+            Exception::StackSegmentFault,
+            Exception::GeneralProtectionFault,
+            Exception::PageFault,
+            Exception::X87FloatingPoint,
+            Exception::AlignmentCheck,
+            Exception::MachineCheck,
+            Exception::SimdFloatingPoint,
+            Exception::Virtualization,
+            Exception::SecurityException,
+        ];
+        exceptions.iter().copied().find(|e| e.vector() == vector)
+    }
+
+    /// Returns whether the cpu pushes an error code for the exception.
+    pub fn has_error_code(self) -> bool {
+        match self {
+            Exception::DoubleFault
+            | Exception::PageFault
+            | Exception::AlignmentCheck
+            | Exception::SecurityException => true,
+            _ => self.has_selector_error_code(),
+        }
+    }
+
+    /// Returns the information passed to the recovery hook for `frame`.
+    pub fn info(self, frame: &TrapFrame) -> ExceptionInfo {
+        ExceptionInfo {
+            exception: self,
+            error_code: if self.has_error_code() {
+                Some(frame.error_code)
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Exception::DivideError => "DIVIDE ERROR",
+            Exception::Debug => "DEBUG",
+            Exception::NonMaskableInterrupt => "NON-MASKABLE INTERRUPT",
+            Exception::Breakpoint => "BREAKPOINT",
+            Exception::Overflow => "OVERFLOW",
+            Exception::BoundRangeExceeded => "BOUND RANGE EXCEEDED",
+            Exception::InvalidOpcode => "INVALID OPCODE",
+            Exception::DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+            Exception::DoubleFault => "DOUBLE FAULT",
+            Exception::InvalidTss => "INVALID TSS",
+            Exception::SegmentNotPresent => "SEGMENT NOT PRESENT",
+            Exception::StackSegmentFault => "STACK-SEGMENT FAULT",
+            Exception::GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            Exception::PageFault => "PAGE FAULT",
+            Exception::X87FloatingPoint => "X87 FLOATING-POINT EXCEPTION",
+            Exception::AlignmentCheck => "ALIGNMENT CHECK",
+            Exception::MachineCheck => "MACHINE CHECK",
+            Exception::SimdFloatingPoint => "SIMD FLOATING-POINT EXCEPTION",
+            Exception::Virtualization => "VIRTUALIZATION EXCEPTION",
+            Exception::SecurityException => "SECURITY EXCEPTION",
+        }
+    }
+
+    /// Returns the short name used by the manuals, like `#GP`.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Exception::DivideError => "#DE",
+            Exception::Debug => "#DB",
+            Exception::NonMaskableInterrupt => "NMI",
+            Exception::Breakpoint => "#BP",
+            Exception::Overflow => "#OF",
+            Exception::BoundRangeExceeded => "#BR",
+            Exception::InvalidOpcode => "#UD",
+            Exception::DeviceNotAvailable => "#NM",
+            Exception::DoubleFault => "#DF",
+            Exception::InvalidTss => "#TS",
+            Exception::SegmentNotPresent => "#NP",
+            Exception::StackSegmentFault => "#SS",
+            Exception::GeneralProtectionFault => "#GP",
+            Exception::PageFault => "#PF",
+            Exception::X87FloatingPoint => "#MF",
+            Exception::AlignmentCheck => "#AC",
+            Exception::MachineCheck => "#MC",
+            Exception::SimdFloatingPoint => "#XM",
+            Exception::Virtualization => "#VE",
+            Exception::SecurityException => "#SX",
+        }
+    }
+
+    /// Returns whether the error code is a segment selector, see `SelectorErrorCode`.
+    pub fn has_selector_error_code(self) -> bool {
+        matches!(
+            self,
+            Exception::InvalidTss
+                | Exception::SegmentNotPresent
+                | Exception::StackSegmentFault
+                | Exception::GeneralProtectionFault
+        )
+    }
+}
+
+/// The descriptor table a selector error code refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// The error code of the exceptions caused by a segment selector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    /// Returns whether the exception happened while delivering an external event.
+    pub fn is_external(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(&self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// Returns the index of the descriptor in its table, the vector for the IDT.
+    pub fn index(&self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "not caused by a segment selector");
+        }
+        write!(f, "{:?} entry {}", self.table(), self.index())?;
+        if self.is_external() {
+            write!(f, ", while delivering an external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// An exception as passed to the recovery hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionInfo {
+    pub exception: Exception,
+    pub error_code: Option<u64>,
+}
+
+impl fmt::Display for ExceptionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, vector {})",
+            self.exception.name(),
+            self.exception.mnemonic(),
+            self.exception.vector()
+        )?;
+        match self.error_code {
+            Some(code) if self.exception.has_selector_error_code() => {
+                write!(f, ", error code {:#x}: {}", code, SelectorErrorCode(code))
+            }
+            Some(code) => write!(f, ", error code {:#x}", code),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What the kernel does after the recovery hook ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Returns to the (possibly changed) stack frame.
+    Resume,
+    /// Prints the diagnostics and panics.
+    Fatal,
+}
+
+/// Called before an exception is treated as fatal.
+///
+/// The hook can fix the cause and resume, skip the instruction by changing the
+/// instruction pointer, change any other register, or kill the task that faulted
+/// by resuming in code that ends it. It runs in the exception handler and must
+/// not take locks the interrupted code could hold.
+pub type ExceptionHook = fn(&ExceptionInfo, &mut TrapFrame) -> Recovery;
+
+static HOOK: RwLock<Option<ExceptionHook>> = RwLock::new(None);
+
+/// Installs the recovery hook and returns the previous one.
+pub fn set_hook(hook: Option<ExceptionHook>) -> Option<ExceptionHook> {
+    without_interrupts(|| core::mem::replace(&mut *HOOK.write(), hook))
+}
+
+/// Runs the recovery hook, returns whether the exception was recovered from.
+pub fn recover(info: &ExceptionInfo, frame: &mut TrapFrame) -> bool {
+    // the exception might have interrupted set_hook.
+    let hook = match HOOK.try_read() {
+        Some(hook) => *hook,
+        None => None,
+    };
+    match hook {
+        Some(hook) => hook(info, frame) == Recovery::Resume,
+        None => false,
+    }
+}
+
+/// Reads the bytes at `addr`, returns `None` if they are not all mapped.
+pub fn instruction_bytes(addr: VirtAddr) -> Option<[u8; INSTRUCTION_BYTES]> {
+    let offset = memory::physical_memory_offset()?;
+    let last = addr.as_u64().checked_add(INSTRUCTION_BYTES as u64 - 1)?;
+    for byte in [addr.as_u64(), last].iter() {
+        let byte = VirtAddr::try_new(*byte).ok()?;
+        unsafe { memory::translate(byte, offset)? };
+    }
+    let mut bytes = [0; INSTRUCTION_BYTES];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { addr.as_ptr::<u8>().add(i).read_volatile() };
+    }
+    Some(bytes)
+}
+
+/// Prints the instruction bytes and every register of the interrupted code to serial.
+pub fn print_state(frame: &TrapFrame) {
+    let rip = frame.instruction_pointer();
+    serial_println!("instruction: {}", Symbolized::new(rip));
+    match instruction_bytes(rip) {
+        Some(bytes) => {
+            use crate::serial_print;
+            serial_print!("bytes:");
+            for byte in bytes.iter() {
+                serial_print!(" {:02x}", byte);
+            }
+            serial_println!();
+        }
+        None => {
+            serial_println!("bytes: (not mapped)");
+        }
+    }
+    serial_println!("{}", frame);
+    let (level_4_table, cr3_flags) = Cr3::read_raw();
+    serial_println!(
+        "   cr0 {:#018x}     cr2 {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64()
+    );
+    serial_println!(
+        "   cr3 {:#018x}     cr4 {:#018x}",
+        level_4_table.start_address().as_u64() | u64::from(cr3_flags),
+        Cr4::read_raw()
+    );
+}
+
+/// Called by the exception entry stubs in `trap` with the saved registers.
+pub(crate) extern "C" fn dispatch(frame: &mut TrapFrame) {
+    let exception = match Exception::from_vector(frame.vector as u8) {
+        Some(exception) => exception,
+        None => panic!("exception stub for unknown vector {}", frame.vector),
+    };
+    match exception {
+        Exception::Breakpoint => interrupts::breakpoint_handler(frame),
+        Exception::DoubleFault => interrupts::double_fault_handler(frame),
+        Exception::PageFault => interrupts::page_fault_handler(frame),
+        _ => handle(exception.info(frame), frame),
+    }
+}
+
+fn handle(info: ExceptionInfo, frame: &mut TrapFrame) {
+    // a machine check cannot be resumed, the state of the machine is unreliable.
+    if info.exception != Exception::MachineCheck && recover(&info, frame) {
+        return;
+    }
+    match info.exception {
+        // traps that do not stop the kernel.
+        Exception::Debug | Exception::NonMaskableInterrupt => {
+            serial_println!("EXCEPTION: {}", info);
+            print_state(frame);
+        }
+        _ => fatal(&info, frame),
+    }
+}
+
+/// Prints the exception, the registers and the backtrace and panics.
+pub fn fatal(info: &ExceptionInfo, frame: &TrapFrame) -> ! {
+    serial_println!("EXCEPTION: {}", info);
+    print_state(frame);
+    crate::backtrace::print_trap_backtrace(frame);
+    panic!(
+        "EXCEPTION: {} at {}",
+        info.exception.name(),
+        Symbolized::new(frame.instruction_pointer())
+    );
+}
@@ -1,3 +1,5 @@
+use crate::memory::stack::{self, StackError};
+use core::cell::UnsafeCell;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -7,25 +9,60 @@ use x86_64::VirtAddr;
 // Usually happens for parent processes as they are waiting for child process' exit status.
 // Thread Stack -> available for every ongoing thread -> contains useful data as long as a thread is alive.
 
+// the double fault handler gets its own stack, so it still runs when a kernel
+// stack overflowed into its guard page: the cpu cannot push the page fault's
+// frame on the full stack and raises a double fault instead. Page faults stay on
+// the current stack because their handler can fault again.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+// the TSS is changed after it was loaded when the static boot stacks are replaced,
+// so it sits in an UnsafeCell.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// the TSS is only changed through install_ist_stack with interrupts disabled.
+unsafe impl Sync for Tss {}
+
 lazy_static! {
     // TSS -> task state segment -> contains information about a task.
     // In protected mode, it is used for hardware task switching.
-    static ref TSS: TaskStateSegment = {
+    // The IST stack starts out as a static without guard page, init_guarded_stacks
+    // replaces it once the heap and the kernel address space are set up.
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_end = stack_start + IST_STACK_SIZE;
             stack_end
         };
-        return tss;
+        return Tss(UnsafeCell::new(tss));
     };
 }
 
+/// Points the IST entry at a new stack.
+///
+/// This function is unsafe because the caller must guarantee that the stack
+/// stays mapped for as long as the entry uses it.
+pub unsafe fn install_ist_stack(index: u16, stack_top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        (*TSS.0.get()).interrupt_stack_table[index as usize] = stack_top;
+    });
+}
+
+/// Replaces the static IST stack with a stack that has a guard page.
+///
+/// Needs the kernel memory, see `memory::init_kernel_memory`.
+pub fn init_guarded_stacks() -> Result<(), StackError> {
+    let stack = stack::allocate_stack("double fault IST", IST_STACK_SIZE as u64)?;
+    unsafe { install_ist_stack(DOUBLE_FAULT_IST_INDEX, stack.top()) };
+    // the TSS uses the stack for the rest of the runtime.
+    core::mem::forget(stack);
+    Ok(())
+}
+
 // double fault happens when there is not entry in IDT (Interrupt Descriptor Table)
 // to catch first fault (first fault can be anything related to hardware or software fault or memory fault).
 // There is triple fault as well which occurs when there is no entry or a handler for double fault.
@@ -53,7 +90,7 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         (
             gdt,
             Selectors {
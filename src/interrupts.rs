@@ -1,5 +1,11 @@
 // during cpu function call, first six integer arguments passed in registers are,
+use crate::apic::{self, ApicError};
+use crate::exceptions::{self, Exception};
+use crate::irq::{self, IrqReturn};
+use crate::symbols::Symbolized;
+use crate::trap::{self, TrapFrame};
 use crate::{gdt, hlt_loop, print, println};
+use core::fmt;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
@@ -45,20 +51,22 @@ use x86_64::{
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt: InterruptDescriptorTable = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // exceptions enter through stubs that save all registers in a TrapFrame,
+        // see `exceptions::dispatch`.
+        let double_fault = trap::install(&mut idt);
 
         // the unsafe block in rust means "Trust me, I know what I am doing.".
         // Basically code outside unsafe block, is rejected by compiler if it thinks it might break something
         // or is accessing wrong address or anything fishy. But adding it in unsafe block, it says I am doing this
         // but you the developer is responsible.
         unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            double_fault.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        // device interrupts go through the dispatcher, see `irq::register`.
+        for (irq, &entry) in irq::ENTRIES.iter().enumerate() {
+            idt[usize::from(irq::vector(irq as u8))].set_handler_fn(entry);
+        }
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         return idt;
     };
 }
@@ -93,11 +101,105 @@ impl InterruptIndex {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    /// Returns the legacy ISA IRQ line of the device.
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+/// The controller that delivers device interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    /// The two chained 8259 PICs, used until `init_apic` succeeds.
+    Pic,
+    Apic,
+}
+
+pub fn controller() -> InterruptController {
+    if apic::is_enabled() {
+        InterruptController::Apic
+    } else {
+        InterruptController::Pic
+    }
+}
+
+/// Switches device interrupts from the 8259 PIC to the local and I/O APIC.
+///
+/// IRQs keep their vectors. When there is no APIC or a line with handlers
+/// cannot be routed, the I/O APIC inputs are masked again, the error is
+/// returned and the PIC stays in use. Needs the kernel memory from
+/// `memory::init_kernel_memory`.
+pub fn init_apic() -> Result<(), ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe { apic::init(irq::route_registered_lines)? };
+        // the PICs are still remapped to 32 and 40, so interrupts they raise
+        // while masked (spurious ones) cannot be mistaken for exceptions.
+        unsafe { mask_pics() };
+        Ok(())
+    })
+}
+
+// masks every line of both PICs through their data ports.
+unsafe fn mask_pics() {
+    use x86_64::instructions::port::Port;
+
+    Port::<u8>::new(0x21).write(0xff);
+    Port::<u8>::new(0xa1).write(0xff);
+}
+
+// picks the port of `irq`'s PIC out of the master's and the slave's, and the bit of the line.
+fn pic_port(irq: u8, master: u16, slave: u16) -> (u16, u8) {
+    if irq < 8 {
+        (master, 1 << irq)
+    } else {
+        (slave, 1 << (irq - 8))
+    }
+}
+
+/// Masks or unmasks a line of the 8259 PICs.
+///
+/// This function is unsafe because unmasking a line without a handler for its
+/// vector causes an exception.
+pub(crate) unsafe fn set_pic_line_masked(irq: u8, masked: bool) {
+    use x86_64::instructions::port::Port;
+
+    let (port, bit) = pic_port(irq, 0x21, 0xa1);
+    let mut port = Port::<u8>::new(port);
+    let mask = port.read();
+    port.write(if masked { mask | bit } else { mask & !bit });
+}
+
+/// Returns whether the PIC is currently servicing `irq`.
+pub(crate) fn pic_line_in_service(irq: u8) -> bool {
+    use x86_64::instructions::port::Port;
+
+    // OCW3 0x0b selects the in-service register for the next read of the command port.
+    let (port, bit) = pic_port(irq, 0x20, 0xa0);
+    let mut port = Port::<u8>::new(port);
+    unsafe {
+        port.write(0x0b);
+        port.read() & bit != 0
+    }
+}
+
+/// Signals the end of the interrupt on `vector` to the controller that delivered it.
+pub fn end_of_interrupt(vector: u8) {
+    match controller() {
+        InterruptController::Apic => apic::end_of_interrupt(),
+        InterruptController::Pic => unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        },
     }
 }
 
+/// Registers the handlers of the timer and the keyboard.
+pub fn init_irqs() {
+    irq::register(InterruptIndex::Timer.irq(), timer_interrupt, 0)
+        .expect("failed to register the timer interrupt");
+    irq::register(InterruptIndex::Keyboard.irq(), keyboard_interrupt, 0)
+        .expect("failed to register the keyboard interrupt");
+}
+
 // static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new(); // creating new idt for our kernel.
 
 pub fn init_dt() {
@@ -105,19 +207,31 @@ pub fn init_dt() {
     IDT.load();
 }
 
-// providing a function to C ABI or x86-interupt ABI.
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    crate::println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+// the exception handlers are called by exceptions::dispatch with the registers
+// of the interrupted code.
+pub(crate) fn breakpoint_handler(frame: &mut TrapFrame) {
+    crate::println!("EXCEPTION: BREAKPOINT\n{}", frame);
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+pub(crate) fn double_fault_handler(frame: &mut TrapFrame) -> ! {
+    use crate::memory::stack;
+    use x86_64::registers::control::Cr2;
+
+    // a kernel stack that overflowed into its guard page leaves no room for the
+    // page fault's frame, so the page fault turns into a double fault.
+    if let Some(name) = stack::guard_page_owner(Cr2::read()) {
+        println!("kernel stack overflow in {}", name);
+    }
+    exceptions::print_state(frame);
+    crate::backtrace::print_trap_backtrace(frame);
+    panic!(
+        "EXCEPTION: DOUBLE FAULT at {}\n{}",
+        Symbolized::new(frame.instruction_pointer()),
+        frame
+    );
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt(_irq: u8, _context: usize) -> IrqReturn {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -141,31 +255,93 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
         }
     }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    IrqReturn::Handled
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+// the local APIC raises it when an interrupt went away before it was delivered,
+// it must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Handles page faults of the kernel.
+///
+/// The fault may hit code that holds any kernel lock, so everything called
+/// from here only uses `try_lock` and `try_with_kernel_memory` and treats a
+/// held lock as an unresolved fault instead of deadlocking.
+pub(crate) fn page_fault_handler(frame: &mut TrapFrame) {
+    use crate::memory::{address_space, lazy, stack};
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+
     // when a page fault occurs, the cpu sets cr2 register for the page fault which contains
     // the virtual address accessed during the page fault or we can say the address which caused
     // page fault.
+    let addr = Cr2::read();
+    // faults in lazily mapped regions are resolved by mapping the page, returning
+    // retries the faulting instruction.
+    if lazy::handle_page_fault(addr, error_code) {
+        return;
+    }
+    // writes to pages shared by AddressSpace::clone_cow copy the page first.
+    if address_space::handle_cow_fault(addr, error_code) {
+        return;
+    }
+    if exceptions::recover(&Exception::PageFault.info(frame), frame) {
+        return;
+    }
+
+    println!("EXCEPTION: PAGE FAULT");
+    // a fault in the guard page below a kernel stack means that stack overflowed.
+    if let Some(name) = stack::guard_page_owner(addr) {
+        println!("kernel stack overflow in {}", name);
+    }
     // error code gives us information about the type of memory access occured -> read/write.
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", addr);
+    println!(
+        "Instruction: {}",
+        Symbolized::new(frame.instruction_pointer())
+    );
     println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    println!("{}", PageFaultDescription(error_code));
+    println!("{}", frame);
+    exceptions::print_state(frame);
+    crate::backtrace::print_trap_backtrace(frame);
     hlt_loop();
 
     // we can read from the current instruction pointer but we cannot write to it.
 }
 
+/// Formats the bits of a page fault error code in plain language.
+pub struct PageFaultDescription(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        let cause = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation on a present page"
+        } else {
+            "page not present"
+        };
+        write!(f, "{} in {} mode: {}", access, mode, cause)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in a page table entry")?;
+        }
+        Ok(())
+    }
+}
+
 // The breakpoint exception is the perfect exception to test exception handling. Its only purpose is to temporarily pause a program when the breakpoint instruction int3 is executed.
 
 #[test_case]
@@ -174,24 +350,17 @@ pub fn test_breakpoint_exception() {
     // x86_64::instructions::interrupts::int3(); // invoking breakpoint exception.
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn timer_interrupt(_irq: u8, _context: usize) -> IrqReturn {
     /*
      * PIC expects an explicit “end of interrupt” (EOI) signal from our interrupt handler.
-     * This signal tells the controller that the interrupt was processed and that the system is ready to receive the next interrupt
+     * This signal tells the controller that the interrupt was processed and that the system is ready to receive the next interrupt.
+     * The dispatcher in irq.rs sends it after the handlers ran.
      */
 
-    print!(".");
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-
-    // We need to be careful to use the correct interrupt vector number,
-    // otherwise we could accidentally delete an important unsent interrupt or cause our system to hang.
-    // This is the reason that the function is unsafe.
-
-    // The hardware timer that we use is called the Programmable Interval Timer or PIT.
+    // The hardware timer that we use is called the Programmable Interval Timer or PIT,
+    // time::init sets how often it fires.
+    crate::time::tick();
+    IrqReturn::Handled
 }
 
 //  Deadlocks occur if a thread tries to acquire a lock that will never become free. Thus the thread hangs indefinitely.
@@ -0,0 +1,274 @@
+use crate::apic::{self, Apic, ApicError, IrqMode};
+use crate::interrupts::{self, InterruptController, PIC_1_OFFSET};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+/// Number of IRQ lines, the 16 ISA lines of the PICs plus the inputs of the first
+/// I/O APIC.
+pub const IRQ_COUNT: usize = 24;
+/// Number of handlers that can share a line.
+pub const MAX_HANDLERS_PER_IRQ: usize = 4;
+/// IRQ `n` is delivered to vector `IRQ_BASE_VECTOR + n`.
+pub const IRQ_BASE_VECTOR: u8 = PIC_1_OFFSET;
+
+// the PICs only have 16 lines.
+const PIC_LINES: u8 = 16;
+const CASCADE_IRQ: u8 = 2;
+
+/// What a handler reports for an interrupt on a shared line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The handler's device raised the interrupt and it was serviced.
+    Handled,
+    /// The interrupt came from another device on the line.
+    NotMine,
+}
+
+/// A handler for an IRQ line, called with the IRQ and the context it was
+/// registered with.
+///
+/// Handlers run with interrupts disabled and must not block. They may register
+/// and unregister handlers.
+pub type IrqHandler = fn(irq: u8, context: usize) -> IrqReturn;
+
+#[derive(Debug)]
+pub enum IrqError {
+    InvalidIrq(u8),
+    /// All `MAX_HANDLERS_PER_IRQ` slots of the line are in use.
+    LineFull(u8),
+    /// The line does not exist on the 8259 PIC.
+    NoPicLine(u8),
+    NotRegistered,
+    Apic(ApicError),
+}
+
+impl From<ApicError> for IrqError {
+    fn from(err: ApicError) -> Self {
+        IrqError::Apic(err)
+    }
+}
+
+/// Identifies a registered handler, see `unregister`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    irq: u8,
+    slot: usize,
+    id: u64,
+}
+
+impl IrqHandle {
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Registration {
+    handler: IrqHandler,
+    context: usize,
+    id: u64,
+}
+
+type Line = [Option<Registration>; MAX_HANDLERS_PER_IRQ];
+
+// only written with interrupts disabled, so the dispatcher never waits for it.
+static HANDLERS: RwLock<[Line; IRQ_COUNT]> = RwLock::new([[None; MAX_HANDLERS_PER_IRQ]; IRQ_COUNT]);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+static COUNTS: [AtomicU64; IRQ_COUNT] = [const { AtomicU64::new(0) }; IRQ_COUNT];
+static UNHANDLED: [AtomicU64; IRQ_COUNT] = [const { AtomicU64::new(0) }; IRQ_COUNT];
+
+/// Returns the vector `irq` is delivered to.
+pub fn vector(irq: u8) -> u8 {
+    IRQ_BASE_VECTOR + irq
+}
+
+/// Adds a handler to the line `irq` and enables the line if it is the first one.
+///
+/// Several handlers can share a line, they are called in the order they were
+/// registered and each must check whether its device raised the interrupt.
+pub fn register(irq: u8, handler: IrqHandler, context: usize) -> Result<IrqHandle, IrqError> {
+    if usize::from(irq) >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let line = &mut handlers[usize::from(irq)];
+        let slot = line
+            .iter()
+            .position(Option::is_none)
+            .ok_or(IrqError::LineFull(irq))?;
+        if line.iter().all(Option::is_none) {
+            enable_line(irq)?;
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        line[slot] = Some(Registration {
+            handler,
+            context,
+            id,
+        });
+        Ok(IrqHandle { irq, slot, id })
+    })
+}
+
+/// Removes a handler, the line is masked when its last handler is removed.
+pub fn unregister(handle: IrqHandle) -> Result<(), IrqError> {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let line = handlers
+            .get_mut(usize::from(handle.irq))
+            .ok_or(IrqError::NotRegistered)?;
+        match line[handle.slot] {
+            Some(registration) if registration.id == handle.id => line[handle.slot] = None,
+            _ => return Err(IrqError::NotRegistered),
+        }
+        if line.iter().all(Option::is_none) {
+            disable_line(handle.irq)?;
+        }
+        Ok(())
+    })
+}
+
+/// Returns the number of handlers registered for `irq`.
+pub fn handler_count(irq: u8) -> usize {
+    HANDLERS
+        .read()
+        .get(usize::from(irq))
+        .map_or(0, |line| line.iter().flatten().count())
+}
+
+/// Returns how many interrupts arrived on the vector of `irq`.
+pub fn count(irq: u8) -> u64 {
+    COUNTS
+        .get(usize::from(irq))
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Returns how many interrupts on the vector of `irq` no handler claimed,
+/// including spurious ones.
+pub fn unhandled_count(irq: u8) -> u64 {
+    UNHANDLED
+        .get(usize::from(irq))
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Routes every line with handlers through the I/O APICs of `apic`, used when
+/// switching from the PIC.
+pub(crate) fn route_registered_lines(apic: &Apic) -> Result<(), ApicError> {
+    let handlers = HANDLERS.read();
+    for (irq, line) in handlers.iter().enumerate() {
+        if line.iter().any(Option::is_some) {
+            route_apic(apic, irq as u8)?;
+        }
+    }
+    Ok(())
+}
+
+fn route_apic(apic: &Apic, irq: u8) -> Result<(), ApicError> {
+    if irq < PIC_LINES {
+        apic.route_irq(irq, vector(irq))
+    } else {
+        // the inputs above the ISA lines are used by PCI devices.
+        apic.route_gsi(u32::from(irq), vector(irq), IrqMode::PCI)
+    }
+}
+
+fn enable_line(irq: u8) -> Result<(), IrqError> {
+    match apic::get() {
+        Some(apic) => route_apic(apic, irq)?,
+        None if irq < PIC_LINES => unsafe {
+            // lines of the slave PIC also need the cascade line of the master.
+            if irq >= 8 {
+                interrupts::set_pic_line_masked(CASCADE_IRQ, false);
+            }
+            interrupts::set_pic_line_masked(irq, false);
+        },
+        None => return Err(IrqError::NoPicLine(irq)),
+    }
+    Ok(())
+}
+
+fn disable_line(irq: u8) -> Result<(), IrqError> {
+    match apic::get() {
+        Some(apic) => {
+            let (gsi, _) = if irq < PIC_LINES {
+                apic.isa_irq_to_gsi(irq)
+            } else {
+                (u32::from(irq), IrqMode::PCI)
+            };
+            apic.set_gsi_masked(gsi, true)?;
+        }
+        None if irq < PIC_LINES => unsafe {
+            interrupts::set_pic_line_masked(irq, true);
+        },
+        None => return Err(IrqError::NoPicLine(irq)),
+    }
+    Ok(())
+}
+
+fn dispatch(irq: u8) {
+    let index = usize::from(irq);
+    COUNTS[index].fetch_add(1, Ordering::Relaxed);
+
+    // the PIC raises IRQ 7 or 15 when a request went away before it was
+    // acknowledged. Those are not in service and must not be acknowledged,
+    // except on the master for the cascade line.
+    let pic = interrupts::controller() == InterruptController::Pic;
+    if pic && (irq == 7 || irq == 15) && !interrupts::pic_line_in_service(irq) {
+        UNHANDLED[index].fetch_add(1, Ordering::Relaxed);
+        if irq == 15 {
+            interrupts::end_of_interrupt(vector(CASCADE_IRQ));
+        }
+        return;
+    }
+
+    // the line is copied so that handlers can change the registrations.
+    let line = HANDLERS.read()[index];
+    let mut handled = false;
+    for registration in line.iter().flatten() {
+        if (registration.handler)(irq, registration.context) == IrqReturn::Handled {
+            handled = true;
+        }
+    }
+    if !handled {
+        UNHANDLED[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // the controller expects an explicit "end of interrupt" before it delivers
+    // the next interrupt of the same or a lower priority.
+    interrupts::end_of_interrupt(vector(irq));
+}
+
+extern "x86-interrupt" fn irq_entry<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(IRQ);
+}
+
+/// The IDT entries of the IRQ vectors, entry `n` dispatches IRQ `n`.
+pub(crate) static ENTRIES: [HandlerFunc; IRQ_COUNT] = [
+    irq_entry::<0>,
+    irq_entry::<1>,
+    irq_entry::<2>,
+    irq_entry::<3>,
+    irq_entry::<4>,
+    irq_entry::<5>,
+    irq_entry::<6>,
+    irq_entry::<7>,
+    irq_entry::<8>,
+    irq_entry::<9>,
+    irq_entry::<10>,
+    irq_entry::<11>,
+    irq_entry::<12>,
+    irq_entry::<13>,
+    irq_entry::<14>,
+    irq_entry::<15>,
+    irq_entry::<16>,
+    irq_entry::<17>,
+    irq_entry::<18>,
+    irq_entry::<19>,
+    irq_entry::<20>,
+    irq_entry::<21>,
+    irq_entry::<22>,
+    irq_entry::<23>,
+];
@@ -13,17 +13,27 @@ use core::panic::PanicInfo;
 // By adding this extern crate statement, we specify that the compiler should try to include it.
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod exceptions;
 pub mod gdt;
 pub mod interrupts;
+pub mod irq;
 pub mod memory;
 pub mod serial;
+pub mod symbols;
+pub mod time;
+pub mod trap;
 pub mod vga_buffer;
 
 pub fn init() {
     gdt::init();
     interrupts::init_dt();
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_irqs();
+    time::init(time::DEFAULT_FREQUENCY);
     // this function is also unsafe because it can cause undefined
     // behavior if the PIC is misconfigured.
     x86_64::instructions::interrupts::enable();
@@ -63,6 +73,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    backtrace::print_backtrace();
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -17,7 +17,7 @@ use rust_os::{allocator, println};
 entry_point!(kernel_boot);
 
 fn kernel_boot(boot_info: &'static BootInfo) -> ! {
-    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use rust_os::memory::{self, BitmapFrameAllocator};
     use x86_64::{structures::paging::Page, structures::paging::Translate, VirtAddr};
 
     println!("Hello World{}", "!");
@@ -25,9 +25,21 @@ fn kernel_boot(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    // kernel code becomes read-only, kernel data no-execute.
+    unsafe { memory::wx::protect_kernel(&mut mapper) }.expect("failed to protect kernel segments");
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    // from here on the heap can grow by mapping more pages.
+    memory::init_kernel_memory(mapper, frame_allocator);
+    memory::report::init(&boot_info.memory_map);
+    memory::report::print_report();
+    // the PIC keeps delivering interrupts if there is no APIC.
+    if let Err(err) = rust_os::interrupts::init_apic() {
+        println!("APIC not used: {:?}", err);
+    }
+    rust_os::gdt::init_guarded_stacks().expect("failed to allocate interrupt stacks");
 
     // map an unused page
     //let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
@@ -100,6 +112,7 @@ fn kernel_boot(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    rust_os::backtrace::print_backtrace();
     rust_os::hlt_loop();
 }
 
@@ -1,8 +1,30 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::arch::x86_64::{__cpuid, CpuidResult};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::mapper::MapToError;
 use x86_64::structures::paging::OffsetPageTable;
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
 use x86_64::{structures::paging::PageTable, PhysAddr, VirtAddr};
 
+pub mod address_space;
+mod bitmap;
+pub mod buddy;
+pub mod dump;
+pub mod lazy;
+pub mod mmio;
+pub mod report;
+pub mod stack;
+pub mod vmm;
+pub mod wx;
+
+pub use address_space::AddressSpace;
+pub use bitmap::BitmapFrameAllocator;
+pub use buddy::BuddyFrameAllocator;
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -28,6 +50,38 @@ pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static
 // then take virtual address as pointer to page table.
 
 pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
+    translate_addr_inner(addr, physical_memory_offset).map(|t| t.phys_addr)
+}
+
+/// The size of the page that maps a translated address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedPageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappedPageSize {
+    /// Returns the size of the page in bytes.
+    pub fn size(self) -> u64 {
+        match self {
+            MappedPageSize::Size4KiB => Size4KiB::SIZE,
+            MappedPageSize::Size2MiB => Size2MiB::SIZE,
+            MappedPageSize::Size1GiB => Size1GiB::SIZE,
+        }
+    }
+}
+
+/// The result of translating a virtual address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys_addr: PhysAddr,
+    pub page_size: MappedPageSize,
+}
+
+/// Same as `translate_addr`, but also returns the size of the page that maps
+/// the address. Huge 2 MiB and 1 GiB pages are supported.
+pub unsafe fn translate(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<Translation> {
     translate_addr_inner(addr, physical_memory_offset)
 }
 
@@ -37,7 +91,7 @@ pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -
 /// the whole body of unsafe functions as an unsafe block. This function must
 /// only be reachable through `unsafe fn` from outside of this module.
 
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
+fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<Translation> {
     use x86_64::registers::control::Cr3;
     use x86_64::structures::paging::page_table::FrameError;
 
@@ -53,7 +107,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
 
     let mut frame = level_4_table_frame;
 
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert frame into a page table reference.
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -64,23 +118,101 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            Err(FrameError::HugeFrame) => {
+                // a huge entry in the P3 table maps a 1 GiB page and one in the P2
+                // table a 2 MiB page. In the P1 table the same bit selects the PAT
+                // entry and the entry still maps a normal 4 KiB page.
+                let page_size = match level {
+                    1 => MappedPageSize::Size1GiB,
+                    2 => MappedPageSize::Size2MiB,
+                    3 => MappedPageSize::Size4KiB,
+                    _ => return None, // the bit is reserved in the P4 table.
+                };
+                let offset = addr.as_u64() & (page_size.size() - 1);
+                // bit 12 of a huge entry is its PAT bit, not part of the address.
+                return Some(Translation {
+                    phys_addr: entry.addr().align_down(page_size.size()) + offset,
+                    page_size,
+                });
+            }
         };
     }
 
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+    Some(Translation {
+        phys_addr: frame.start_address() + u64::from(addr.page_offset()),
+        page_size: MappedPageSize::Size4KiB,
+    })
 }
 
 // translation of physical_memory_offset should point to physical address 0.
-// For this translation HugeFrame is used.
+// The bootloader maps the physical memory with huge pages, so this goes through the HugeFrame case.
 
 // 'static -> available for complete runtime of the kernel.
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    // everything mapped from here on can be no-execute.
+    if !wx::enable_nx() {
+        crate::println!("no-execute bit not supported, memory stays executable");
+    }
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+// zero until `init` is called, the bootloader never maps physical memory at zero.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the offset the complete physical memory is mapped at.
+///
+/// Returns `None` if `init` was not called yet.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset)),
+    }
+}
+
+/// The kernel's page table mapper together with the frame allocator backing it.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BitmapFrameAllocator,
+}
+
+// filled by init_kernel_memory so that code without access to the boot info
+// (e.g. the heap when it needs to grow) can map pages.
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+/// Hands the mapper and frame allocator over to the kernel, after this call they
+/// are only reachable through `with_kernel_memory`.
+pub fn init_kernel_memory(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    without_interrupts(|| {
+        *KERNEL_MEMORY.lock() = Some(KernelMemory {
+            mapper,
+            frame_allocator,
+        });
+    });
+}
+
+/// Runs `f` with the kernel mapper and frame allocator.
+///
+/// Returns `None` if `init_kernel_memory` was not called yet. `f` must not allocate
+/// heap memory, because the heap itself calls this function when it grows.
+pub fn with_kernel_memory<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut KernelMemory) -> R,
+{
+    without_interrupts(|| KERNEL_MEMORY.lock().as_mut().map(f))
+}
+
+/// Same as `with_kernel_memory`, but returns `None` instead of waiting when the
+/// kernel memory is in use already.
+pub fn try_with_kernel_memory<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut KernelMemory) -> R,
+{
+    without_interrupts(|| KERNEL_MEMORY.try_lock()?.as_mut().map(f))
+}
+
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
@@ -90,7 +222,7 @@ pub fn create_example_mapping(
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+    let flags = wx::supported_flags(Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE);
 
     let map_to_result = unsafe {
         // FIXME: this is not safe, we do it only for testing.
@@ -99,6 +231,56 @@ pub fn create_example_mapping(
     map_to_result.expect("map_to failed.").flush();
 }
 
+/// Maps a 2 MiB page to the given frame through the passed mapper.
+///
+/// This function is unsafe because the caller must guarantee that the frame is
+/// unused and that the new mapping does not break memory safety.
+pub unsafe fn map_2mib_page(
+    page: Page<Size2MiB>,
+    frame: PhysFrame<Size2MiB>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size2MiB>> {
+    // map_to sets the HUGE_PAGE flag in the P2 entry for us.
+    let flags = wx::supported_flags(flags);
+    mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    Ok(())
+}
+
+/// Maps a 1 GiB page to the given frame through the passed mapper.
+///
+/// This function is unsafe because the caller must guarantee that the frame is
+/// unused, that the new mapping does not break memory safety and that the CPU
+/// supports 1 GiB pages (see `supports_1gib_pages`).
+pub unsafe fn map_1gib_page(
+    page: Page<Size1GiB>,
+    frame: PhysFrame<Size1GiB>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size1GiB>> {
+    let flags = wx::supported_flags(flags);
+    mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    Ok(())
+}
+
+/// Executes the `cpuid` instruction for the given leaf.
+pub fn cpuid(leaf: u32) -> CpuidResult {
+    // `__cpuid` is a safe function on newer toolchains.
+    #[allow(unused_unsafe)]
+    unsafe {
+        __cpuid(leaf)
+    }
+}
+
+/// Returns whether the CPU supports 1 GiB pages.
+pub fn supports_1gib_pages() -> bool {
+    // the Page1GB bit lives in the extended leaf 0x8000_0001, which older CPUs don't have.
+    let max_extended_leaf = cpuid(0x8000_0000).eax;
+    max_extended_leaf >= 0x8000_0001 && cpuid(0x8000_0001).edx & (1 << 26) != 0
+}
+
 // simple frame allocator.
 // EmptyFrameAllocator always returns 'None'.
 pub struct EmptyFrameAllocator;
@@ -113,6 +295,9 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 }
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// Every allocation walks the memory map again and frames can never be freed,
+/// use `BitmapFrameAllocator` for anything long running.
 // Same as how linked list works.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
@@ -0,0 +1,562 @@
+use super::vmm::KERNEL_VMM_START;
+use super::{
+    cpuid, physical_memory_offset, try_with_kernel_memory, with_kernel_memory, wx, KernelMemory,
+};
+use core::sync::atomic::{AtomicU16, Ordering};
+use x86_64::instructions::tlb::{self, Pcid};
+use x86_64::registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Marks leaf entries whose frame was allocated by the address space and is
+/// freed with it.
+const OWNED_FRAME: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Marks read-only leaf entries whose frame is shared by `clone_cow` and copied
+/// on the first write.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+// the leaf entries decide about the access rights, so the tables above them
+// allow everything.
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
+
+// 0 is used by the kernel page tables, the others are handed out round robin.
+// Switching to a table flushes the entries of its PCID, so reusing one is fine.
+const MAX_PCID: u16 = 4096;
+static NEXT_PCID: AtomicU16 = AtomicU16::new(1);
+static PCID_ENABLED: spin::Once<bool> = spin::Once::new();
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// `memory::init_kernel_memory` was not called yet.
+    NotInitialized,
+    FrameAllocationFailed,
+    /// The range overlaps a level 4 entry that is shared with the kernel.
+    KernelRange(VirtAddr),
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+    Flags(FlagUpdateError),
+}
+
+/// A set of page tables that shares the kernel mappings and has private user
+/// mappings.
+///
+/// The level 4 entries that are present in the kernel page tables when the
+/// address space is created are copied, so the kernel (including the heap and
+/// everything mapped through `vmm`) stays mapped after switching. All other
+/// level 4 entries belong to the address space; only they can hold user
+/// mappings and they are freed on drop.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    pcid: Option<Pcid>,
+    // one bit for every level 4 entry copied from the kernel.
+    kernel_entries: [u64; 8],
+}
+
+impl AddressSpace {
+    /// Allocates a new level 4 table with the kernel's entries copied.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let pcid = if pcid_enabled() {
+            let next = NEXT_PCID.fetch_add(1, Ordering::Relaxed) % (MAX_PCID - 1) + 1;
+            Some(Pcid::new(next).unwrap())
+        } else {
+            None
+        };
+        with_kernel_memory(|memory| {
+            // later kernel mappings in this slot then show up in every address space.
+            prepopulate_entry(
+                memory,
+                Page::containing_address(VirtAddr::new(KERNEL_VMM_START)),
+            )?;
+
+            let level_4_frame = memory
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+            let mut space = AddressSpace {
+                level_4_frame,
+                pcid,
+                kernel_entries: [0; 8],
+            };
+            let table = unsafe { &mut *table_ptr(level_4_frame) };
+            let kernel_table = memory.mapper.level_4_table();
+            for (index, entry) in kernel_table.iter().enumerate() {
+                if entry.is_unused() {
+                    table[index].set_unused();
+                } else {
+                    table[index] = entry.clone();
+                    space.kernel_entries[index / 64] |= 1 << (index % 64);
+                }
+            }
+            Ok(space)
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized))
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns whether the range only touches level 4 entries that are not
+    /// shared with the kernel.
+    pub fn is_user_range(&self, start: VirtAddr, size: u64) -> bool {
+        if size == 0 {
+            return true;
+        }
+        let end = match start.as_u64().checked_add(size - 1) {
+            Some(end) if end < USER_END => VirtAddr::new(end),
+            _ => return false,
+        };
+        let first = usize::from(start.p4_index());
+        let last = usize::from(end.p4_index());
+        (first..=last).all(|index| !self.is_kernel_entry(index))
+    }
+
+    fn is_kernel_entry(&self, index: usize) -> bool {
+        self.kernel_entries[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Maps `size` bytes at `start` to new zeroed frames that are freed with
+    /// the address space.
+    ///
+    /// `USER_ACCESSIBLE` is added to `flags`.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        self.check_user_range(start, size)?;
+        let flags =
+            (flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | OWNED_FRAME)
+                - COPY_ON_WRITE;
+        let flags = wx::supported_flags(flags);
+        let offset = physical_memory_offset().ok_or(AddressSpaceError::NotInitialized)?;
+        with_kernel_memory(|memory| {
+            let mut mapper = unsafe { self.mapper() };
+            for page in pages(start, size) {
+                let frame = memory
+                    .frame_allocator
+                    .allocate_frame()
+                    .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+                // the page is not mapped in the active address space, so the frame
+                // is cleared through the physical memory mapping.
+                let frame_ptr: *mut u8 = (offset + frame.start_address().as_u64()).as_mut_ptr();
+                unsafe { core::ptr::write_bytes(frame_ptr, 0, 4096) };
+                let result = unsafe {
+                    mapper.map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        TABLE_FLAGS,
+                        &mut memory.frame_allocator,
+                    )
+                };
+                match result {
+                    Ok(flush) => flush.flush(),
+                    Err(err) => {
+                        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+                        return Err(AddressSpaceError::Map(err));
+                    }
+                }
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized))
+    }
+
+    /// Maps `page` to an existing frame, the frame is not freed with the
+    /// address space and stays shared with clones of it.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frame
+    /// can be accessed through `page` without violating memory safety.
+    pub unsafe fn map_to(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        self.check_user_range(page.start_address(), 4096)?;
+        let flags = (flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+            - OWNED_FRAME
+            - COPY_ON_WRITE;
+        let flags = wx::supported_flags(flags);
+        with_kernel_memory(|memory| {
+            let mut mapper = self.mapper();
+            mapper
+                .map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    TABLE_FLAGS,
+                    &mut memory.frame_allocator,
+                )
+                .map(|flush| flush.flush())
+                .map_err(AddressSpaceError::Map)
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized))
+    }
+
+    /// Unmaps the range, frames mapped through `map` are freed.
+    ///
+    /// Pages that are not mapped are skipped.
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        self.check_user_range(start, size)?;
+        with_kernel_memory(|memory| {
+            let mut mapper = unsafe { self.mapper() };
+            for page in pages(start, size) {
+                let owned = match mapper.translate(page.start_address()) {
+                    TranslateResult::Mapped { flags, .. } => flags.contains(OWNED_FRAME),
+                    _ => continue,
+                };
+                let (frame, flush) = mapper.unmap(page).map_err(AddressSpaceError::Unmap)?;
+                flush.flush();
+                if owned {
+                    unsafe { memory.frame_allocator.deallocate_frame(frame) };
+                }
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized))
+    }
+
+    /// Changes the flags of all mapped pages in the range.
+    ///
+    /// `USER_ACCESSIBLE` is added to `flags`, pages that are not mapped are skipped.
+    /// Pages whose frame is shared with a clone stay copy-on-write instead of
+    /// becoming writable.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        self.check_user_range(start, size)?;
+        let flags = (flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+            - OWNED_FRAME
+            - COPY_ON_WRITE;
+        let flags = wx::supported_flags(flags);
+        with_kernel_memory(|memory| {
+            let mut mapper = unsafe { self.mapper() };
+            for page in pages(start, size) {
+                let (frame, old_flags) = match mapper.translate(page.start_address()) {
+                    TranslateResult::Mapped { frame, flags, .. } => (frame, flags),
+                    _ => continue,
+                };
+                let mut new_flags = flags | (old_flags & OWNED_FRAME);
+                let frame = PhysFrame::containing_address(frame.start_address());
+                if new_flags.contains(PageTableFlags::WRITABLE | OWNED_FRAME)
+                    && memory.frame_allocator.ref_count(frame) > 1
+                {
+                    new_flags.remove(PageTableFlags::WRITABLE);
+                    new_flags.insert(COPY_ON_WRITE);
+                }
+                unsafe { mapper.update_flags(page, new_flags) }
+                    .map_err(AddressSpaceError::Flags)?
+                    .flush();
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized))
+    }
+
+    /// Creates a copy of the address space that shares all frames with it.
+    ///
+    /// Writable frames mapped through `map` become read-only in both address
+    /// spaces and are copied on the first write to them, see `handle_cow_fault`.
+    /// Frames mapped through `map_to` stay shared.
+    pub fn clone_cow(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+        let child = AddressSpace::new()?;
+        let result = with_kernel_memory(|memory| unsafe {
+            let mut child_mapper = child.mapper();
+            let table = &mut *table_ptr(self.level_4_frame);
+            for (index, entry) in table.iter_mut().enumerate() {
+                if self.is_kernel_entry(index) || entry.is_unused() {
+                    continue;
+                }
+                // the kernel started to use the entry after this address space
+                // was created.
+                let addr = (index as u64) << 39;
+                if child.is_kernel_entry(index) {
+                    return Err(AddressSpaceError::KernelRange(VirtAddr::new(addr)));
+                }
+                let frame = entry.frame().unwrap();
+                share_table(frame, 3, addr, &mut child_mapper, memory)?;
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized));
+        // entries of this address space were made read-only.
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        result.map(|()| child)
+    }
+
+    /// Returns the physical address and flags `addr` is mapped to.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match unsafe { self.mapper() }.translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the address space is loaded in `Cr3`.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches to the address space.
+    ///
+    /// This function is unsafe because references into user mappings of the
+    /// previous address space become invalid.
+    pub unsafe fn activate(&self) {
+        match self.pcid {
+            Some(pcid) => Cr3::write_pcid(self.level_4_frame, pcid),
+            None => Cr3::write(self.level_4_frame, Cr3Flags::empty()),
+        }
+    }
+
+    fn check_user_range(&self, start: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        if self.is_user_range(start, size) {
+            Ok(())
+        } else {
+            Err(AddressSpaceError::KernelRange(start))
+        }
+    }
+
+    // the mapper must not be used on the level 4 entries shared with the kernel,
+    // they are checked by `check_user_range`.
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        let offset = physical_memory_offset().expect("memory::init was not called");
+        OffsetPageTable::new(&mut *table_ptr(self.level_4_frame), offset)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe { activate_kernel() };
+        }
+        with_kernel_memory(|memory| unsafe {
+            let table = &mut *table_ptr(self.level_4_frame);
+            for (index, entry) in table.iter_mut().enumerate() {
+                if !self.is_kernel_entry(index) && !entry.is_unused() {
+                    free_table(entry.frame().unwrap(), 3, memory);
+                    entry.set_unused();
+                }
+            }
+            memory.frame_allocator.deallocate_frame(self.level_4_frame);
+        });
+    }
+}
+
+/// Switches back to the kernel page tables.
+///
+/// This function is unsafe because references into user mappings of the
+/// active address space become invalid.
+pub unsafe fn activate_kernel() {
+    let frame = with_kernel_memory(kernel_level_4_frame).expect("kernel memory is not initialized");
+    if pcid_enabled() {
+        Cr3::write_pcid(frame, Pcid::new(0).unwrap());
+    } else {
+        Cr3::write(frame, Cr3Flags::empty());
+    }
+}
+
+/// Returns whether address spaces are switched with process context identifiers.
+///
+/// PCIDs are enabled on first use if the cpu supports them.
+pub fn pcid_enabled() -> bool {
+    *PCID_ENABLED.call_once(|| {
+        let supported = cpuid(1).ecx & (1 << 17) != 0;
+        // the active PCID is 0 because it was never enabled before.
+        if supported {
+            unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+        }
+        supported
+    })
+}
+
+/// Resolves a write fault on a copy-on-write page of the active address space.
+///
+/// The frame is copied unless no other address space references it anymore,
+/// then the page is made writable. Returns `true` if the write can be retried.
+pub fn handle_cow_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_violation =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_violation) || addr.as_u64() >= USER_END {
+        return false;
+    }
+    let entry = match unsafe { leaf_entry(Cr3::read().0, addr) } {
+        Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return false,
+    };
+    try_with_kernel_memory(|memory| unsafe {
+        let old_frame = entry.frame().unwrap();
+        let flags = (entry.flags() | PageTableFlags::WRITABLE) - COPY_ON_WRITE;
+        if memory.frame_allocator.ref_count(old_frame) > 1 {
+            let new_frame = match memory.frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            let offset = physical_memory_offset().unwrap();
+            let src: *const u8 = (offset + old_frame.start_address().as_u64()).as_ptr();
+            let dst: *mut u8 = (offset + new_frame.start_address().as_u64()).as_mut_ptr();
+            core::ptr::copy_nonoverlapping(src, dst, 4096);
+            entry.set_frame(new_frame, flags);
+            // drops the reference of this address space.
+            memory.frame_allocator.deallocate_frame(old_frame);
+        } else {
+            entry.set_flags(flags);
+        }
+        tlb::flush(addr);
+        true
+    })
+    .unwrap_or(false)
+}
+
+// returns the level 1 entry mapping `addr`, if all tables on the way are present.
+unsafe fn leaf_entry(
+    level_4_frame: PhysFrame,
+    addr: VirtAddr,
+) -> Option<&'static mut PageTableEntry> {
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+    let mut table = &mut *table_ptr(level_4_frame);
+    for index in indexes.iter() {
+        let entry = &table[*index];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = &mut *table_ptr(entry.frame().ok()?);
+    }
+    let entry = &mut table[addr.p1_index()];
+    if entry.flags().contains(PageTableFlags::PRESENT) {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+// maps every leaf below the table into `child`, owned frames are shared and
+// writable ones become copy-on-write.
+unsafe fn share_table(
+    frame: PhysFrame,
+    level: u8,
+    base: u64,
+    child: &mut OffsetPageTable,
+    memory: &mut KernelMemory,
+) -> Result<(), AddressSpaceError> {
+    let table = &mut *table_ptr(frame);
+    let shift = 12 + 9 * (u64::from(level) - 1);
+    for (index, entry) in table.iter_mut().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let addr = base | ((index as u64) << shift);
+        if level > 1 {
+            share_table(entry.frame().unwrap(), level - 1, addr, child, memory)?;
+            continue;
+        }
+        let frame = entry.frame().unwrap();
+        let mut flags = entry.flags();
+        let owned = flags.contains(OWNED_FRAME);
+        if owned {
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COPY_ON_WRITE);
+                entry.set_flags(flags);
+            }
+            memory.frame_allocator.share_frame(frame);
+        }
+        let page = Page::containing_address(VirtAddr::new(addr));
+        let result = child.map_to_with_table_flags(
+            page,
+            frame,
+            flags,
+            TABLE_FLAGS,
+            &mut memory.frame_allocator,
+        );
+        match result {
+            // the child is not active.
+            Ok(flush) => flush.ignore(),
+            Err(err) => {
+                if owned {
+                    memory.frame_allocator.deallocate_frame(frame);
+                }
+                return Err(AddressSpaceError::Map(err));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn kernel_level_4_frame(memory: &mut KernelMemory) -> PhysFrame {
+    let offset = physical_memory_offset().expect("memory::init was not called");
+    let table = memory.mapper.level_4_table() as *mut PageTable as u64;
+    PhysFrame::containing_address(PhysAddr::new(table - offset.as_u64()))
+}
+
+// makes sure the kernel level 4 entry of `page` points to a level 3 table.
+fn prepopulate_entry(memory: &mut KernelMemory, page: Page) -> Result<(), AddressSpaceError> {
+    let index = page.p4_index();
+    if !memory.mapper.level_4_table()[index].is_unused() {
+        return Ok(());
+    }
+    let frame = memory
+        .frame_allocator
+        .allocate_frame()
+        .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+    unsafe { (*table_ptr(frame)).zero() };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory.mapper.level_4_table()[index].set_frame(frame, flags);
+    Ok(())
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    let offset = physical_memory_offset().expect("memory::init was not called");
+    (offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+fn pages(start: VirtAddr, size: u64) -> impl Iterator<Item = Page> {
+    let first = Page::containing_address(start);
+    let count = (start.as_u64() % 4096 + size).div_ceil(4096);
+    (0..count).map(move |i| first + i)
+}
+
+// frees the table at `level` and everything below it.
+unsafe fn free_table(frame: PhysFrame, level: u8, memory: &mut KernelMemory) {
+    let table = &mut *table_ptr(frame);
+    for entry in table.iter_mut() {
+        if entry.is_unused() {
+            continue;
+        }
+        if level > 1 {
+            free_table(entry.frame().unwrap(), level - 1, memory);
+        } else if entry.flags().contains(OWNED_FRAME) {
+            memory
+                .frame_allocator
+                .deallocate_frame(entry.frame().unwrap());
+        }
+        entry.set_unused();
+    }
+    memory.frame_allocator.deallocate_frame(frame);
+}
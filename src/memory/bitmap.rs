@@ -0,0 +1,191 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const BITS_PER_WORD: usize = 64;
+
+/// A FrameAllocator that keeps one bit for every physical 4 KiB frame.
+///
+/// A set bit means the frame is in use (or was never usable), a cleared bit means
+/// the frame is free. The bitmap itself is stored at the start of the first usable
+/// region that is big enough to hold it and is accessed through the complete
+/// physical memory mapping at `physical_memory_offset`.
+///
+/// Allocated frames can be shared: `share_frame` adds a reference to a frame and
+/// `deallocate_frame` drops one, the frame is only freed when its last reference
+/// is dropped. The extra references are counted in an array stored right after
+/// the bitmap.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // the number of references beyond the first for every frame.
+    shares: &'static mut [u16],
+    // every word before this index is known to be full.
+    next_word: usize,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a BitmapFrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, that all frames marked as `USABLE` in it are really
+    /// unused and that the complete physical memory is mapped at the passed
+    /// `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // frames after the last usable one are never handed out, so the bitmap
+        // only has to cover the memory up to there.
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let word_count = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (word_count * core::mem::size_of::<u64>()) as u64;
+        let share_count = word_count * BITS_PER_WORD;
+        let shares_bytes = (share_count * core::mem::size_of::<u16>()) as u64;
+        let bitmap_frames = (bitmap_bytes + shares_bytes).div_ceil(Size4KiB::SIZE);
+
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .expect("no usable region is large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_frame_number;
+
+        let virt = physical_memory_offset + bitmap_start * Size4KiB::SIZE;
+        let bitmap = core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u64>(), word_count);
+        let shares_virt = virt + bitmap_bytes;
+        let shares = core::slice::from_raw_parts_mut(shares_virt.as_mut_ptr::<u16>(), share_count);
+        for share in shares.iter_mut() {
+            *share = 0;
+        }
+
+        // start with every frame marked as used and then release the usable ones.
+        // This also keeps the padding bits of the last word from being handed out.
+        for word in bitmap.iter_mut() {
+            *word = u64::MAX;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            shares,
+            next_word: 0,
+            total_frames: 0,
+            free_frames: 0,
+        };
+        for region in usable_regions() {
+            for index in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.clear_bit(index as usize);
+                allocator.total_frames += 1;
+            }
+        }
+
+        // the frames holding the bitmap and the share counts are in use from now on.
+        for index in bitmap_start..bitmap_start + bitmap_frames {
+            allocator.set_bit(index as usize);
+        }
+        allocator.free_frames = allocator.total_frames - bitmap_frames as usize;
+        allocator
+    }
+
+    /// Returns the number of usable frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Returns the number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of frames that are currently allocated.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Adds a reference to an allocated frame.
+    ///
+    /// The frame is only freed after `deallocate_frame` was called once more
+    /// than `share_frame`.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(
+            index < self.shares.len() && self.is_set(index),
+            "sharing a frame that is not allocated: {:?}",
+            frame
+        );
+        self.shares[index] = self.shares[index]
+            .checked_add(1)
+            .expect("too many references to a frame");
+    }
+
+    /// Returns the number of references to the frame, 0 if it is free.
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        let index = frame_index(frame);
+        if index >= self.shares.len() || !self.is_set(index) {
+            return 0;
+        }
+        usize::from(self.shares[index]) + 1
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / Size4KiB::SIZE) as usize
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * Size4KiB::SIZE))
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // full words are skipped, so every frame is looked at only a constant
+        // number of times between two frees.
+        while self.next_word < self.bitmap.len() {
+            let word = self.bitmap[self.next_word];
+            if word != u64::MAX {
+                let index = self.next_word * BITS_PER_WORD + word.trailing_ones() as usize;
+                self.set_bit(index);
+                self.free_frames -= 1;
+                return Some(frame_at(index));
+            }
+            self.next_word += 1;
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(
+            index / BITS_PER_WORD < self.bitmap.len() && self.is_set(index),
+            "deallocating a frame that is not allocated: {:?}",
+            frame
+        );
+        if self.shares[index] > 0 {
+            // the frame is still referenced elsewhere.
+            self.shares[index] -= 1;
+            return;
+        }
+        self.clear_bit(index);
+        self.free_frames += 1;
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
+    }
+}
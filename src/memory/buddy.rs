@@ -0,0 +1,220 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The largest block order. A block of order `n` is `2^n` frames big, so order 10
+/// blocks are 4 MiB and every 2 MiB huge page (order 9) can be served.
+pub const MAX_ORDER: usize = 10;
+
+// marks the end of a free list, physical address 0 is never usable anyway.
+const END_OF_LIST: u64 = u64::MAX;
+
+/// A buddy-system FrameAllocator for physically contiguous allocations.
+///
+/// Free blocks of every order are kept in singly linked lists. The link to the next
+/// free block is stored in the first bytes of the free block itself, which is
+/// reached through the complete physical memory mapping, so the allocator needs no
+/// memory of its own. When a block is freed it is merged with its buddy for as long
+/// as the buddy is free as well.
+pub struct BuddyFrameAllocator {
+    free_lists: [u64; MAX_ORDER + 1],
+    physical_memory_offset: VirtAddr,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Create a BuddyFrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, that all frames marked as `USABLE` in it are really
+    /// unused and that the complete physical memory is mapped at the passed
+    /// `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let mut allocator = BuddyFrameAllocator {
+            free_lists: [END_OF_LIST; MAX_ORDER + 1],
+            physical_memory_offset,
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        let usable_regions = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+        for region in usable_regions {
+            allocator.add_range(region.range.start_addr(), region.range.end_addr());
+        }
+        allocator
+    }
+
+    /// Splits the physical range into the largest naturally aligned blocks and
+    /// puts them on the free lists.
+    unsafe fn add_range(&mut self, start: u64, end: u64) {
+        let mut addr = start;
+        while addr < end {
+            let mut order = MAX_ORDER;
+            while !addr.is_multiple_of(block_size(order)) || addr + block_size(order) > end {
+                order -= 1;
+            }
+            self.push(addr, order);
+            self.total_frames += 1 << order;
+            self.free_frames += 1 << order;
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocates `2^order` physically contiguous frames, aligned to their size.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        // find the smallest free block that is big enough.
+        let mut current = order;
+        while self.free_lists[current] == END_OF_LIST {
+            current += 1;
+            if current > MAX_ORDER {
+                return None;
+            }
+        }
+
+        let addr = unsafe { self.pop(current) };
+        // split it and give the upper halves back until it has the requested size.
+        while current > order {
+            current -= 1;
+            unsafe { self.push(addr + block_size(current), current) };
+        }
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Allocates at least `count` physically contiguous frames.
+    ///
+    /// The block has to be freed again with `deallocate(frame, order_for(count))`.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        self.allocate(order_for(count))
+    }
+
+    /// Frees a block that was returned by `allocate` with the same `order`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the block
+    /// is no longer used and was allocated from this allocator with this order.
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        let mut addr = frame.start_address().as_u64();
+        let mut order = order;
+        self.free_frames += 1 << order;
+
+        // merge with the buddy as long as it is free, the merged block starts at
+        // the lower address of the two.
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    /// Returns the number of usable frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Returns the number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of free blocks of the given order.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut current = self.free_lists[order];
+        while current != END_OF_LIST {
+            count += 1;
+            current = unsafe { *self.link(current) };
+        }
+        count
+    }
+
+    fn link(&self, block: u64) -> *mut u64 {
+        (self.physical_memory_offset + block).as_mut_ptr()
+    }
+
+    unsafe fn push(&mut self, block: u64, order: usize) {
+        *self.link(block) = self.free_lists[order];
+        self.free_lists[order] = block;
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> u64 {
+        let block = self.free_lists[order];
+        self.free_lists[order] = *self.link(block);
+        block
+    }
+
+    /// Removes the block from the free list of the given order, returns false if
+    /// it was not on that list.
+    unsafe fn remove(&mut self, block: u64, order: usize) -> bool {
+        if self.free_lists[order] == block {
+            self.pop(order);
+            return true;
+        }
+        let mut previous = self.free_lists[order];
+        while previous != END_OF_LIST {
+            let next = *self.link(previous);
+            if next == block {
+                *self.link(previous) = *self.link(block);
+                return true;
+            }
+            previous = next;
+        }
+        false
+    }
+}
+
+/// Returns the size in bytes of a block of the given order.
+fn block_size(order: usize) -> u64 {
+    Size4KiB::SIZE << order
+}
+
+/// Returns the smallest order whose blocks hold at least `count` frames.
+pub fn order_for(count: usize) -> usize {
+    let mut order = 0;
+    while (1 << order) < count {
+        order += 1;
+    }
+    order
+}
+
+const HUGE_PAGE_ORDER: usize = 9; // 2 MiB / 4 KiB = 2^9 frames.
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(0)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        // blocks are aligned to their size, so this can't fail.
+        let frame = self.allocate(HUGE_PAGE_ORDER)?;
+        PhysFrame::from_start_address(frame.start_address()).ok()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate(frame, 0)
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate(
+            PhysFrame::containing_address(frame.start_address()),
+            HUGE_PAGE_ORDER,
+        )
+    }
+}
@@ -0,0 +1,210 @@
+use super::{physical_memory_offset, MappedPageSize};
+use crate::serial_println;
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// A range of contiguous virtual memory mapped to contiguous physical memory
+/// with the same flags and page size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub virt_start: VirtAddr,
+    pub phys_start: PhysAddr,
+    pub size: u64,
+    pub page_size: MappedPageSize,
+    /// The effective flags: writable and user accessible only if every level
+    /// allows it, not executable if any level forbids it.
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    pub fn phys_end(&self) -> PhysAddr {
+        self.phys_start + self.size
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags.contains(PageTableFlags::WRITABLE)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        !self.flags.contains(PageTableFlags::NO_EXECUTE)
+    }
+
+    pub fn is_user_accessible(&self) -> bool {
+        self.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    }
+
+    pub fn is_global(&self) -> bool {
+        self.flags.contains(PageTableFlags::GLOBAL)
+    }
+
+    // the virtual end is compared as a number, the range after the last page
+    // of the address space would not be canonical.
+    fn continues_with(&self, next: &MappedRange) -> bool {
+        self.page_size == next.page_size
+            && self.flags == next.flags
+            && self.virt_start.as_u64().wrapping_add(self.size) == next.virt_start.as_u64()
+            && self.phys_end() == next.phys_start
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        let page_size = match self.page_size {
+            MappedPageSize::Size4KiB => "4K",
+            MappedPageSize::Size2MiB => "2M",
+            MappedPageSize::Size1GiB => "1G",
+        };
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x}-{:#014x} {:>10} KiB {} r{}{}{}{}",
+            self.virt_start.as_u64(),
+            self.virt_start.as_u64().wrapping_add(self.size),
+            self.phys_start.as_u64(),
+            self.phys_end().as_u64(),
+            self.size / 1024,
+            page_size,
+            flag(self.is_writable(), 'w'),
+            flag(self.is_executable(), 'x'),
+            flag(self.is_user_accessible(), 'u'),
+            flag(self.is_global(), 'g'),
+        )
+    }
+}
+
+/// Calls `f` for every present mapping of the hierarchy below `level_4_table`,
+/// with contiguous mappings coalesced into one range.
+///
+/// Does not allocate or take locks, so it can be used from a panic handler.
+///
+/// This function is unsafe because the caller must guarantee that the complete
+/// physical memory is mapped at `physical_memory_offset` and that the tables
+/// are not modified during the walk.
+pub unsafe fn for_each_range<F>(
+    level_4_table: &PageTable,
+    physical_memory_offset: VirtAddr,
+    mut f: F,
+) where
+    F: FnMut(&MappedRange),
+{
+    let mut pending: Option<MappedRange> = None;
+    let mut emit = |mapping: MappedRange| match pending.as_mut() {
+        Some(range) if range.continues_with(&mapping) => range.size += mapping.size,
+        _ => {
+            if let Some(range) = pending.replace(mapping) {
+                f(&range);
+            }
+        }
+    };
+    let parent_flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    walk_table(
+        level_4_table,
+        4,
+        0,
+        parent_flags,
+        physical_memory_offset,
+        &mut emit,
+    );
+    if let Some(range) = pending {
+        f(&range);
+    }
+}
+
+/// Same as `for_each_range` for the hierarchy that is active in `Cr3`.
+///
+/// Returns `false` without calling `f` if `memory::init` was not called yet.
+pub fn for_each_active_range<F>(f: F) -> bool
+where
+    F: FnMut(&MappedRange),
+{
+    let offset = match physical_memory_offset() {
+        Some(offset) => offset,
+        None => return false,
+    };
+    let (frame, _) = Cr3::read();
+    // only read through a shared reference, the mapper may hold the `&mut`.
+    let table_ptr: *const PageTable = (offset + frame.start_address().as_u64()).as_ptr();
+    unsafe { for_each_range(&*table_ptr, offset, f) };
+    true
+}
+
+/// Prints all present mappings of the active page tables to serial.
+pub fn dump_active() {
+    serial_println!("page tables at {:?}:", Cr3::read().0.start_address());
+    let printed = for_each_active_range(|range| {
+        serial_println!("  {}", range);
+    });
+    if !printed {
+        serial_println!("  physical memory offset unknown, memory::init was not called");
+    }
+}
+
+unsafe fn walk_table(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    parent_flags: PageTableFlags,
+    physical_memory_offset: VirtAddr,
+    emit: &mut dyn FnMut(MappedRange),
+) {
+    let shift = 12 + 9 * (u64::from(level) - 1);
+    for (index, entry) in table.iter().enumerate() {
+        let entry_flags = entry.flags();
+        if !entry_flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let mut virt = base | ((index as u64) << shift);
+        // the upper half has to be sign extended to be canonical.
+        if level == 4 && index >= 256 {
+            virt |= 0xffff_0000_0000_0000;
+        }
+        let flags = effective_flags(parent_flags, entry_flags);
+
+        let page_size = match level {
+            1 => Some(MappedPageSize::Size4KiB),
+            2 if entry_flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappedPageSize::Size2MiB),
+            3 if entry_flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappedPageSize::Size1GiB),
+            _ => None,
+        };
+        match page_size {
+            Some(page_size) => {
+                // these change on every access and would split otherwise identical ranges.
+                let mut flags = flags - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+                if page_size != MappedPageSize::Size4KiB {
+                    flags.remove(PageTableFlags::HUGE_PAGE);
+                }
+                emit(MappedRange {
+                    virt_start: VirtAddr::new(virt),
+                    // huge entries keep the PAT bit in the lowest address bit.
+                    phys_start: entry.addr().align_down(page_size.size()),
+                    size: page_size.size(),
+                    page_size,
+                    flags,
+                });
+            }
+            None => {
+                let next_ptr: *const PageTable =
+                    (physical_memory_offset + entry.addr().as_u64()).as_ptr();
+                walk_table(
+                    &*next_ptr,
+                    level - 1,
+                    virt,
+                    flags,
+                    physical_memory_offset,
+                    emit,
+                );
+            }
+        }
+    }
+}
+
+fn effective_flags(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut flags = entry - (inherited - parent);
+    if parent.contains(PageTableFlags::NO_EXECUTE) {
+        flags.insert(PageTableFlags::NO_EXECUTE);
+    }
+    flags
+}
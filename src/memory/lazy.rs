@@ -0,0 +1,118 @@
+use super::try_with_kernel_memory;
+use super::vmm::{self, VirtRange, VmmError};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+const MAX_LAZY_REGIONS: usize = 32;
+
+// read by the page fault handler, so it must not depend on the heap.
+static LAZY_REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    Mutex::new([None; MAX_LAZY_REGIONS]);
+
+#[derive(Debug, Clone, Copy)]
+struct LazyRegion {
+    range: VirtRange,
+    flags: PageTableFlags,
+}
+
+/// Identifies a registered lazy region, see `unregister`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyHandle(usize);
+
+#[derive(Debug)]
+pub enum LazyError {
+    /// All lazy region slots are taken.
+    TooManyRegions,
+    Vmm(VmmError),
+}
+
+/// Registers a range whose pages are mapped on first access.
+///
+/// A page fault on a not yet mapped page of the range maps a new zeroed frame
+/// with `flags` there and resumes the faulting code.
+pub fn register(range: VirtRange, flags: PageTableFlags) -> Result<LazyHandle, LazyError> {
+    let region = LazyRegion {
+        range,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+    without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        let (index, slot) = regions
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.is_none())
+            .ok_or(LazyError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(LazyHandle(index))
+    })
+}
+
+/// Stops mapping pages of the region on demand.
+///
+/// Pages that were mapped already stay mapped, `vmm::free` unmaps them and
+/// skips the others.
+pub fn unregister(handle: LazyHandle) {
+    without_interrupts(|| LAZY_REGIONS.lock()[handle.0] = None);
+}
+
+/// Reserves `size` bytes of kernel virtual memory that are mapped on demand.
+///
+/// Unlike `vmm::allocate`, no frames are used until the memory is touched, which
+/// makes this useful for large sparse buffers.
+pub fn reserve(size: u64, flags: PageTableFlags) -> Result<(VirtRange, LazyHandle), LazyError> {
+    let range = vmm::reserve(size).ok_or(LazyError::Vmm(VmmError::OutOfVirtualMemory))?;
+    match register(range, flags) {
+        Ok(handle) => Ok((range, handle)),
+        Err(err) => {
+            unsafe { vmm::release(range) };
+            Err(err)
+        }
+    }
+}
+
+/// Maps the faulting page if `addr` lies in a lazy region.
+///
+/// Returns `true` if the fault was resolved and the faulting instruction can be
+/// retried.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // lazy pages are kernel pages that are not present yet, anything else is a
+    // real fault.
+    if error_code.intersects(
+        PageFaultErrorCode::PROTECTION_VIOLATION
+            | PageFaultErrorCode::USER_MODE
+            | PageFaultErrorCode::MALFORMED_TABLE,
+    ) {
+        return false;
+    }
+    let region = match find_region(addr) {
+        Some(region) => region,
+        None => return false,
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return false;
+    }
+
+    let page = Page::containing_address(addr);
+    let result = try_with_kernel_memory(|memory| vmm::map_new_frame(page, region.flags, memory));
+    match result {
+        Some(Ok(())) => true,
+        // another path mapped the page in between, retrying will succeed.
+        Some(Err(MapToError::PageAlreadyMapped(_))) => true,
+        Some(Err(_)) | None => false,
+    }
+}
+
+fn find_region(addr: VirtAddr) -> Option<LazyRegion> {
+    let regions = LAZY_REGIONS.try_lock()?;
+    regions
+        .iter()
+        .flatten()
+        .find(|region| region.range.contains(addr))
+        .copied()
+}
@@ -0,0 +1,180 @@
+use super::vmm::{self, VirtRange, VmmError};
+use super::{cpuid, with_kernel_memory, wx};
+use spin::Once;
+use x86_64::structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// How the CPU may cache accesses to a mapped device range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes to the device in program order, for registers.
+    Uncached,
+    /// Reads are cached, writes go straight to the device.
+    WriteThrough,
+    /// Writes may be combined into bursts, for framebuffers. Falls back to
+    /// `Uncached` when the CPU has no PAT.
+    WriteCombining,
+    /// Normally cached, for ranges that the physical memory mapping already
+    /// maps, which uses this mode.
+    WriteBack,
+}
+
+// The PAT (page attribute table) maps the PAT, PCD and PWT bits of a page table
+// entry to a memory type. Entry 4 (only the PAT bit set) is write-back by default
+// and nothing uses it, so it is reprogrammed to write-combining.
+const IA32_PAT: u32 = 0x277;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const WRITE_COMBINING_PAT_INDEX: u64 = 4;
+
+// in a P1 entry the bit that marks huge pages in the higher levels selects the PAT entry.
+const PAT_BIT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+static WRITE_COMBINING: Once<bool> = Once::new();
+
+/// Returns whether the CPU has a PAT.
+fn supports_pat() -> bool {
+    cpuid(1).edx & (1 << 16) != 0
+}
+
+// programs the PAT entry for write-combining, returns false if there is no PAT.
+fn init_write_combining() -> bool {
+    *WRITE_COMBINING.call_once(|| {
+        if !supports_pat() {
+            return false;
+        }
+        use x86_64::registers::model_specific::Msr;
+
+        let mut pat = Msr::new(IA32_PAT);
+        let shift = WRITE_COMBINING_PAT_INDEX * 8;
+        unsafe {
+            let value = pat.read() & !(0xff << shift);
+            pat.write(value | PAT_WRITE_COMBINING << shift);
+            // caches and TLB might still hold the old memory type.
+            core::arch::asm!("wbinvd", options(nostack));
+        }
+        x86_64::instructions::tlb::flush_all();
+        true
+    })
+}
+
+/// A mapping of device memory, it is unmapped when dropped.
+pub struct MmioRegion {
+    range: VirtRange,
+    phys_addr: PhysAddr,
+    // offset of phys_addr in its page.
+    offset: usize,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// Returns the virtual address of the first mapped byte.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.range.start() + self.offset
+    }
+
+    /// Returns the physical address the region was mapped from.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a pointer to the first mapped byte.
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt_addr().as_mut_ptr()
+    }
+
+    /// Reads a value at the given byte offset with a volatile read.
+    ///
+    /// This function is unsafe because reading device registers can have side effects.
+    pub unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+        self.check_bounds::<T>(offset);
+        self.as_ptr::<u8>().add(offset).cast::<T>().read_volatile()
+    }
+
+    /// Writes a value at the given byte offset with a volatile write.
+    ///
+    /// This function is unsafe because writing device registers can have side effects.
+    pub unsafe fn write<T: Copy>(&self, offset: usize, value: T) {
+        self.check_bounds::<T>(offset);
+        self.as_ptr::<u8>()
+            .add(offset)
+            .cast::<T>()
+            .write_volatile(value)
+    }
+
+    fn check_bounds<T>(&self, offset: usize) {
+        assert!(
+            offset + core::mem::size_of::<T>() <= self.size,
+            "MMIO access at offset {:#x} outside of region of size {:#x}",
+            offset,
+            self.size
+        );
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        unsafe { vmm::unmap(self.range) };
+    }
+}
+
+/// Maps `size` bytes of device memory at `phys_addr` into the kernel address space.
+///
+/// This function is unsafe because the caller must guarantee that the physical
+/// range belongs to a device (or is otherwise not used as normal memory) and that
+/// it is not mapped with a conflicting cache mode anywhere else.
+///
+/// Returns `VmmError::EmptyRange` if `size` is 0.
+pub unsafe fn ioremap(
+    phys_addr: PhysAddr,
+    size: usize,
+    mode: CacheMode,
+) -> Result<MmioRegion, VmmError> {
+    if size == 0 {
+        return Err(VmmError::EmptyRange);
+    }
+    let phys_start = phys_addr.align_down(Size4KiB::SIZE);
+    let offset = (phys_addr - phys_start) as usize;
+    let range = vmm::reserve((offset + size) as u64).ok_or(VmmError::OutOfVirtualMemory)?;
+
+    let write_combining = mode == CacheMode::WriteCombining && init_write_combining();
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    flags |= match mode {
+        CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+        CacheMode::WriteCombining if write_combining => PageTableFlags::empty(),
+        CacheMode::WriteBack => PageTableFlags::empty(),
+        // PCD and PWT together select the strong uncached type.
+        _ => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+    };
+    let flags = wx::supported_flags(flags);
+
+    if let Err(err) = vmm::map_phys(&range, phys_start, flags) {
+        vmm::release(range);
+        return Err(err);
+    }
+    if write_combining {
+        // map_to refuses the PAT bit because it looks like the huge page bit,
+        // so it is set on the mapped entries afterwards.
+        with_kernel_memory(|memory| {
+            for page in range.pages() {
+                set_pat_bit(&mut memory.mapper, page, flags);
+            }
+        });
+    }
+
+    Ok(MmioRegion {
+        range,
+        phys_addr,
+        offset,
+        size,
+    })
+}
+
+unsafe fn set_pat_bit(mapper: &mut impl Mapper<Size4KiB>, page: Page, flags: PageTableFlags) {
+    if let Ok(flush) = mapper.update_flags(page, flags | PAT_BIT) {
+        flush.flush();
+    }
+}
@@ -0,0 +1,189 @@
+use super::with_kernel_memory;
+use crate::serial_println;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+
+// the bootloader only knows a handful of region types, unknown firmware types
+// beyond this are counted in `other_bytes`.
+const MAX_REGION_TYPES: usize = 24;
+
+static MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+
+/// The regions of one type in the memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionSummary {
+    pub region_type: MemoryRegionType,
+    pub regions: usize,
+    pub bytes: u64,
+}
+
+/// A summary of the physical memory map passed by the bootloader.
+#[derive(Clone)]
+pub struct MemoryReport {
+    memory_map: &'static MemoryMap,
+    summaries: [Option<RegionSummary>; MAX_REGION_TYPES],
+    other_bytes: u64,
+    // free and total frames of the kernel frame allocator.
+    frames: Option<(usize, usize)>,
+}
+
+impl MemoryReport {
+    /// Summarises the regions of `memory_map` by type.
+    pub fn new(memory_map: &'static MemoryMap) -> Self {
+        let mut report = MemoryReport {
+            memory_map,
+            summaries: [None; MAX_REGION_TYPES],
+            other_bytes: 0,
+            frames: with_kernel_memory(|memory| {
+                let allocator = &memory.frame_allocator;
+                (allocator.free_frames(), allocator.total_frames())
+            }),
+        };
+        for region in memory_map.iter() {
+            let bytes =
+                (region.range.end_frame_number - region.range.start_frame_number) * Size4KiB::SIZE;
+            report.add(region.region_type, bytes);
+        }
+        report
+    }
+
+    fn add(&mut self, region_type: MemoryRegionType, bytes: u64) {
+        for slot in self.summaries.iter_mut() {
+            match slot {
+                Some(summary) if summary.region_type == region_type => {
+                    summary.regions += 1;
+                    summary.bytes += bytes;
+                    return;
+                }
+                Some(_) => {}
+                None => {
+                    *slot = Some(RegionSummary {
+                        region_type,
+                        regions: 1,
+                        bytes,
+                    });
+                    return;
+                }
+            }
+        }
+        self.other_bytes += bytes;
+    }
+
+    /// Returns the summary of every region type in the memory map, in the order
+    /// the types first appear.
+    pub fn summaries(&self) -> impl Iterator<Item = &RegionSummary> {
+        self.summaries.iter().flatten()
+    }
+
+    /// Returns the number of bytes in regions of the given type.
+    pub fn bytes(&self, region_type: MemoryRegionType) -> u64 {
+        self.summaries()
+            .find(|summary| summary.region_type == region_type)
+            .map_or(0, |summary| summary.bytes)
+    }
+
+    /// Returns the number of bytes covered by the memory map.
+    pub fn total_bytes(&self) -> u64 {
+        self.summaries().map(|summary| summary.bytes).sum::<u64>() + self.other_bytes
+    }
+
+    /// Returns the number of free frames of the kernel frame allocator when the
+    /// report was made, or `None` if the kernel memory was not set up yet.
+    pub fn free_frames(&self) -> Option<usize> {
+        self.frames.map(|(free, _)| free)
+    }
+
+    pub fn total_frames(&self) -> Option<usize> {
+        self.frames.map(|(_, total)| total)
+    }
+
+    pub fn memory_map(&self) -> &'static MemoryMap {
+        self.memory_map
+    }
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "physical memory map:")?;
+        for region in self.memory_map.iter() {
+            writeln!(
+                f,
+                "  {:#012x}-{:#012x} {:?}",
+                region.range.start_addr(),
+                region.range.end_addr(),
+                region.region_type
+            )?;
+        }
+        for summary in self.summaries() {
+            writeln!(
+                f,
+                "  {:<24} {:>3} regions {:>10} KiB",
+                region_type_name(summary.region_type),
+                summary.regions,
+                summary.bytes / 1024
+            )?;
+        }
+        if self.other_bytes > 0 {
+            writeln!(f, "  {:<24} {:>10} KiB", "other", self.other_bytes / 1024)?;
+        }
+        write!(f, "  total {} KiB", self.total_bytes() / 1024)?;
+        if let Some((free, total)) = self.frames {
+            write!(
+                f,
+                "\n  frame allocator: {} of {} frames free ({} KiB)",
+                free,
+                total,
+                free as u64 * Size4KiB::SIZE / 1024
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// `MemoryRegionType` only implements Debug, which does not support padding.
+fn region_type_name(region_type: MemoryRegionType) -> &'static str {
+    match region_type {
+        MemoryRegionType::Usable => "usable",
+        MemoryRegionType::InUse => "in use",
+        MemoryRegionType::Reserved => "reserved",
+        MemoryRegionType::AcpiReclaimable => "acpi reclaimable",
+        MemoryRegionType::AcpiNvs => "acpi nvs",
+        MemoryRegionType::BadMemory => "bad memory",
+        MemoryRegionType::Kernel => "kernel",
+        MemoryRegionType::KernelStack => "kernel stack",
+        MemoryRegionType::PageTable => "page tables",
+        MemoryRegionType::Bootloader => "bootloader",
+        MemoryRegionType::FrameZero => "frame zero",
+        MemoryRegionType::Empty => "empty",
+        MemoryRegionType::BootInfo => "boot info",
+        MemoryRegionType::Package => "package",
+        _ => "firmware specific",
+    }
+}
+
+/// Remembers the memory map for `report` and `print_report`.
+pub fn init(memory_map: &'static MemoryMap) {
+    without_interrupts(|| *MEMORY_MAP.lock() = Some(memory_map));
+}
+
+/// Returns a report of the memory map passed to `init`, or `None` if `init` was
+/// not called yet.
+pub fn report() -> Option<MemoryReport> {
+    let memory_map = without_interrupts(|| *MEMORY_MAP.lock())?;
+    Some(MemoryReport::new(memory_map))
+}
+
+/// Prints the memory report to serial.
+pub fn print_report() {
+    match report() {
+        Some(report) => {
+            serial_println!("{}", report);
+        }
+        None => {
+            serial_println!("memory map unknown, memory::report::init was not called");
+        }
+    }
+}
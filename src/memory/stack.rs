@@ -0,0 +1,110 @@
+use super::vmm::{self, VirtRange, VmmError};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+const GUARD_SIZE: u64 = Size4KiB::SIZE;
+const MAX_STACKS: usize = 64;
+
+// the guard page ranges of all stacks, so the fault handlers can name the
+// stack that overflowed.
+static GUARD_PAGES: Mutex<[Option<(u64, &'static str)>; MAX_STACKS]> =
+    Mutex::new([None; MAX_STACKS]);
+
+/// A kernel stack with an unmapped guard page below it.
+///
+/// Stacks grow down, so overflowing the stack touches the guard page and causes
+/// a double fault (the page fault cannot be delivered on the full stack) instead
+/// of silently overwriting other memory. The stack is
+/// unmapped and its frames are freed when it is dropped.
+pub struct KernelStack {
+    name: &'static str,
+    // the whole reserved range, including the guard page at its start.
+    range: VirtRange,
+}
+
+impl KernelStack {
+    /// Returns the address the stack pointer starts at.
+    pub fn top(&self) -> VirtAddr {
+        self.range.end()
+    }
+
+    /// Returns the lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.range.start() + GUARD_SIZE
+    }
+
+    /// Returns the usable size of the stack in bytes.
+    pub fn size(&self) -> u64 {
+        self.range.size() - GUARD_SIZE
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let guard = self.range.start().as_u64();
+        without_interrupts(|| {
+            let mut guards = GUARD_PAGES.lock();
+            if let Some(entry) = guards
+                .iter_mut()
+                .find(|e| matches!(e, Some((start, _)) if *start == guard))
+            {
+                *entry = None;
+            }
+        });
+        unsafe {
+            vmm::free(self.range.subrange(GUARD_SIZE, self.size()));
+            vmm::release(self.range.subrange(0, GUARD_SIZE));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StackError {
+    /// All guard page slots are taken.
+    TooManyStacks,
+    Vmm(VmmError),
+}
+
+/// Allocates a stack of at least `size` bytes with a guard page below it.
+///
+/// The name is used to report overflows of the stack.
+pub fn allocate_stack(name: &'static str, size: u64) -> Result<KernelStack, StackError> {
+    let range =
+        vmm::reserve(GUARD_SIZE + size).ok_or(StackError::Vmm(VmmError::OutOfVirtualMemory))?;
+    let guard = range.start().as_u64();
+
+    let registered = without_interrupts(|| {
+        let mut guards = GUARD_PAGES.lock();
+        let slot = guards.iter_mut().find(|e| e.is_none())?;
+        *slot = Some((guard, name));
+        Some(())
+    });
+    if registered.is_none() {
+        unsafe { vmm::release(range) };
+        return Err(StackError::TooManyStacks);
+    }
+
+    // only the part above the guard page is mapped.
+    let stack = KernelStack { name, range };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    vmm::map(&range.subrange(GUARD_SIZE, stack.size()), flags).map_err(StackError::Vmm)?;
+    Ok(stack)
+}
+
+/// Returns the name of the stack whose guard page contains the address.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    let addr = addr.as_u64();
+    // called from the fault handlers, so it must not wait for the lock.
+    let guards = GUARD_PAGES.try_lock()?;
+    guards
+        .iter()
+        .flatten()
+        .find(|(start, _)| *start <= addr && addr < start + GUARD_SIZE)
+        .map(|(_, name)| *name)
+}
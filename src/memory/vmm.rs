@@ -0,0 +1,334 @@
+use super::{physical_memory_offset, with_kernel_memory, wx, KernelMemory};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Start of the higher half window the kernel hands out virtual ranges from.
+pub const KERNEL_VMM_START: u64 = 0xffff_9000_0000_0000;
+/// Size of the window, one level 4 entry (512 GiB).
+pub const KERNEL_VMM_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+// the free ranges are kept in a fixed array because the heap itself gets its
+// virtual range from here.
+const MAX_FREE_RANGES: usize = 128;
+
+static KERNEL_VMM: Mutex<VirtualRangeAllocator> = Mutex::new(VirtualRangeAllocator::new(
+    KERNEL_VMM_START,
+    KERNEL_VMM_START + KERNEL_VMM_SIZE,
+));
+
+/// A page aligned range of kernel virtual addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRange {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl VirtRange {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// Returns the first address after the range.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Returns the part of the range that starts `offset` bytes in, both values
+    /// are rounded up to whole pages.
+    pub fn subrange(&self, offset: u64, size: u64) -> VirtRange {
+        let (offset, size) = (page_align(offset), page_align(size));
+        assert!(offset + size <= self.size, "subrange out of bounds");
+        VirtRange {
+            start: self.start + offset,
+            size,
+        }
+    }
+
+    /// Returns the pages of the range.
+    pub fn pages(&self) -> impl Iterator<Item = Page> {
+        let start = Page::containing_address(self.start);
+        let end = Page::containing_address(self.end());
+        Page::range(start, end)
+    }
+}
+
+#[derive(Debug)]
+pub enum VmmError {
+    /// No free virtual range of the requested size is left.
+    OutOfVirtualMemory,
+    /// A mapping of zero bytes was requested.
+    EmptyRange,
+    /// `memory::init_kernel_memory` was not called yet.
+    NotInitialized,
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+}
+
+/// A first fit allocator for virtual address ranges.
+///
+/// The free ranges are sorted by address and merged with their neighbours when
+/// a range is released, so the number of entries stays small.
+pub struct VirtualRangeAllocator {
+    free: [(u64, u64); MAX_FREE_RANGES],
+    len: usize,
+}
+
+impl VirtualRangeAllocator {
+    /// Creates an allocator for the range [start, end).
+    pub const fn new(start: u64, end: u64) -> Self {
+        let mut free = [(0, 0); MAX_FREE_RANGES];
+        free[0] = (start, end);
+        VirtualRangeAllocator { free, len: 1 }
+    }
+
+    /// Takes `size` bytes aligned to `align` from the first free range they fit in.
+    pub fn reserve(&mut self, size: u64, align: u64) -> Option<u64> {
+        for i in 0..self.len {
+            let (start, end) = self.free[i];
+            let aligned = match start.checked_add(align - 1) {
+                Some(addr) => addr & !(align - 1),
+                None => continue,
+            };
+            let reserved_end = match aligned.checked_add(size) {
+                Some(reserved_end) if reserved_end <= end => reserved_end,
+                _ => continue,
+            };
+
+            // the free range shrinks to the padding before and the rest after the
+            // reserved part, a range that needs both only fits if there is room
+            // for another entry.
+            match (start < aligned, reserved_end < end) {
+                (true, true) => {
+                    if self.len == MAX_FREE_RANGES {
+                        continue;
+                    }
+                    self.free.copy_within(i + 1..self.len, i + 2);
+                    self.free[i] = (start, aligned);
+                    self.free[i + 1] = (reserved_end, end);
+                    self.len += 1;
+                }
+                (true, false) => self.free[i].1 = aligned,
+                (false, true) => self.free[i].0 = reserved_end,
+                (false, false) => self.remove(i),
+            }
+            return Some(aligned);
+        }
+        None
+    }
+
+    /// Gives the range back to the allocator.
+    pub fn release(&mut self, start: u64, size: u64) {
+        let end = start + size;
+        // ranges that can't be recorded anymore are lost, that only wastes addresses.
+        let _ = self.insert(start, end);
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.free.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+    }
+
+    fn insert(&mut self, start: u64, end: u64) -> Option<()> {
+        let index = self.free[..self.len]
+            .iter()
+            .position(|&(s, _)| s > start)
+            .unwrap_or(self.len);
+
+        let merges_previous = index > 0 && self.free[index - 1].1 == start;
+        let merges_next = index < self.len && self.free[index].0 == end;
+        match (merges_previous, merges_next) {
+            (true, true) => {
+                self.free[index - 1].1 = self.free[index].1;
+                self.remove(index);
+            }
+            (true, false) => self.free[index - 1].1 = end,
+            (false, true) => self.free[index].0 = start,
+            (false, false) => {
+                if self.len == MAX_FREE_RANGES {
+                    return None;
+                }
+                self.free.copy_within(index..self.len, index + 1);
+                self.free[index] = (start, end);
+                self.len += 1;
+            }
+        }
+        Some(())
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+fn page_align(size: u64) -> u64 {
+    align_up(size, Size4KiB::SIZE)
+}
+
+/// Reserves a range of at least `size` bytes without mapping it.
+pub fn reserve(size: u64) -> Option<VirtRange> {
+    reserve_aligned(size, Size4KiB::SIZE)
+}
+
+/// Reserves a range of at least `size` bytes that starts at a multiple of `align`.
+pub fn reserve_aligned(size: u64, align: u64) -> Option<VirtRange> {
+    let size = page_align(size);
+    let start = without_interrupts(|| KERNEL_VMM.lock().reserve(size, align))?;
+    Some(VirtRange {
+        start: VirtAddr::new(start),
+        size,
+    })
+}
+
+/// Gives a reserved range back.
+///
+/// This function is unsafe because the caller must guarantee that nothing in
+/// the range is mapped or used anymore.
+pub unsafe fn release(range: VirtRange) {
+    without_interrupts(|| KERNEL_VMM.lock().release(range.start.as_u64(), range.size));
+}
+
+/// Reserves a range of at least `size` bytes and maps it to new zeroed frames.
+///
+/// The range has to be given back with `free`.
+pub fn allocate(size: u64, flags: PageTableFlags) -> Result<VirtRange, VmmError> {
+    let range = reserve(size).ok_or(VmmError::OutOfVirtualMemory)?;
+    if let Err(err) = map(&range, flags) {
+        unsafe { release(range) };
+        return Err(err);
+    }
+    Ok(range)
+}
+
+/// Maps a reserved range to new zeroed frames.
+pub fn map(range: &VirtRange, flags: PageTableFlags) -> Result<(), VmmError> {
+    with_kernel_memory(|memory| map_new_frames(*range, flags, memory))
+        .ok_or(VmmError::NotInitialized)?
+        .map_err(VmmError::Map)
+}
+
+// maps every page of the range to a new zeroed frame, on failure the pages
+// mapped so far are unmapped again.
+fn map_new_frames(
+    range: VirtRange,
+    flags: PageTableFlags,
+    memory: &mut KernelMemory,
+) -> Result<(), MapToError<Size4KiB>> {
+    for (mapped, page) in range.pages().enumerate() {
+        if let Err(err) = map_new_frame(page, flags, memory) {
+            let mapped_size = mapped as u64 * Size4KiB::SIZE;
+            unsafe { unmap_pages(range.start, mapped_size, true, memory) };
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+pub(super) fn map_new_frame(
+    page: Page,
+    flags: PageTableFlags,
+    memory: &mut KernelMemory,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = memory
+        .frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    // the frame might still hold data of its previous user. It is cleared
+    // through the physical memory mapping, the new mapping may be read-only.
+    // KernelMemory only exists after memory::init set the offset.
+    let offset = physical_memory_offset().unwrap();
+    let frame_ptr: *mut u8 = (offset + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { core::ptr::write_bytes(frame_ptr, 0, 4096) };
+
+    let flags = wx::supported_flags(flags);
+    let result = unsafe {
+        memory
+            .mapper
+            .map_to(page, frame, flags, &mut memory.frame_allocator)
+    };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
+
+/// Maps the range to the physical memory starting at `phys_start`.
+///
+/// This function is unsafe because the caller must guarantee that the physical
+/// memory may be accessed with the given flags, e.g. that it is device memory
+/// or owned by the caller.
+pub unsafe fn map_phys(
+    range: &VirtRange,
+    phys_start: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), VmmError> {
+    let flags = wx::supported_flags(flags);
+    let result = with_kernel_memory(|memory| {
+        for (i, page) in range.pages().enumerate() {
+            let frame = PhysFrame::containing_address(phys_start + i as u64 * Size4KiB::SIZE);
+            match memory
+                .mapper
+                .map_to(page, frame, flags, &mut memory.frame_allocator)
+            {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    unmap_pages(range.start, i as u64 * Size4KiB::SIZE, false, memory);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    });
+    result
+        .ok_or(VmmError::NotInitialized)?
+        .map_err(VmmError::Map)
+}
+
+/// Unmaps and releases a range returned by `allocate` and frees its frames.
+///
+/// This function is unsafe because the caller must guarantee that the range is
+/// not used anymore.
+pub unsafe fn free(range: VirtRange) {
+    with_kernel_memory(|memory| unmap_pages(range.start, range.size, true, memory));
+    release(range);
+}
+
+/// Unmaps and releases a range mapped with `map_phys`, the frames are not freed.
+///
+/// This function is unsafe because the caller must guarantee that the range is
+/// not used anymore.
+pub unsafe fn unmap(range: VirtRange) {
+    with_kernel_memory(|memory| unmap_pages(range.start, range.size, false, memory));
+    release(range);
+}
+
+// pages that are not mapped are skipped.
+unsafe fn unmap_pages(start: VirtAddr, size: u64, free_frames: bool, memory: &mut KernelMemory) {
+    let range = VirtRange { start, size };
+    for page in range.pages() {
+        if let Ok((frame, flush)) = memory.mapper.unmap(page) {
+            flush.flush();
+            if free_frames {
+                memory.frame_allocator.deallocate_frame(frame);
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use super::cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Efer, EferFlags};
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+extern "C" {
+    // defined by the linker at the ELF header, which is mapped as part of the
+    // first loadable segment.
+    static __ehdr_start: u8;
+}
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// the part of an ELF64 program header we need.
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+struct ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+#[derive(Debug)]
+pub enum ProtectError {
+    /// No ELF header was found at `__ehdr_start`.
+    InvalidElfHeader,
+    /// A kernel segment is both writable and executable.
+    WritableAndExecutable(VirtAddr),
+    Flags(FlagUpdateError),
+}
+
+/// Returns whether the cpu supports the no-execute bit in page table entries.
+pub fn supports_nx() -> bool {
+    // the NX bit lives in the extended leaf 0x8000_0001, which older CPUs don't have.
+    let max_extended_leaf = cpuid(0x8000_0000).eax;
+    max_extended_leaf >= 0x8000_0001 && cpuid(0x8000_0001).edx & (1 << 20) != 0
+}
+
+/// Enables the no-execute bit in page table entries and makes writes to
+/// read-only pages fault in kernel mode too.
+///
+/// Must be called before any page is mapped with `NO_EXECUTE`, the bit is
+/// reserved otherwise. Returns false when the cpu has no NX bit, only write
+/// protection is enabled then and `supported_flags` leaves `NO_EXECUTE` out.
+pub fn enable_nx() -> bool {
+    let nx = supports_nx();
+    unsafe {
+        if nx {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        // without WP the cpu ignores the writable bit for kernel accesses.
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    NX_ENABLED.store(nx, Ordering::Relaxed);
+    nx
+}
+
+/// Returns whether `enable_nx` enabled the no-execute bit.
+pub fn nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Removes `NO_EXECUTE` from `flags` unless the no-execute bit is enabled,
+/// every mapping goes through this because the bit is reserved otherwise.
+pub fn supported_flags(flags: PageTableFlags) -> PageTableFlags {
+    if nx_enabled() {
+        flags
+    } else {
+        flags - PageTableFlags::NO_EXECUTE
+    }
+}
+
+/// Remaps the kernel's own segments so that code is read-only and everything
+/// else is not executable.
+///
+/// The segments are taken from the program headers of the kernel ELF file.
+/// Nothing is changed if a segment is both writable and executable.
+///
+/// This function is unsafe because the caller must guarantee that `enable_nx`
+/// was called and that `mapper` maps the active page tables.
+pub unsafe fn protect_kernel(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), ProtectError> {
+    let segments = load_segments()?;
+    for segment in segments.clone() {
+        if segment.p_flags & PF_W != 0 && segment.p_flags & PF_X != 0 {
+            return Err(ProtectError::WritableAndExecutable(VirtAddr::new(
+                segment.p_vaddr,
+            )));
+        }
+    }
+
+    for segment in segments {
+        let mut flags = PageTableFlags::PRESENT;
+        if segment.p_flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if segment.p_flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        let flags = supported_flags(flags);
+        let start = VirtAddr::new(segment.p_vaddr);
+        let end = start + segment.p_memsz - 1u64;
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(end),
+        );
+        for page in pages {
+            mapper
+                .update_flags(page, flags)
+                .map_err(ProtectError::Flags)?
+                .flush();
+        }
+    }
+    Ok(())
+}
+
+// returns the loadable segments of the kernel that are not empty.
+unsafe fn load_segments() -> Result<impl Iterator<Item = ProgramHeader> + Clone, ProtectError> {
+    let header = &__ehdr_start as *const u8;
+    if core::slice::from_raw_parts(header, ELF_MAGIC.len()) != ELF_MAGIC {
+        return Err(ProtectError::InvalidElfHeader);
+    }
+    // offsets of e_phoff, e_phentsize and e_phnum in the ELF64 header.
+    let phoff = header.add(0x20).cast::<u64>().read_unaligned() as usize;
+    let phentsize = header.add(0x36).cast::<u16>().read_unaligned() as usize;
+    let phnum = header.add(0x38).cast::<u16>().read_unaligned() as usize;
+
+    Ok((0..phnum)
+        .map(move |i| {
+            header
+                .add(phoff + i * phentsize)
+                .cast::<ProgramHeader>()
+                .read_unaligned()
+        })
+        .filter(|segment| segment.p_type == PT_LOAD && segment.p_memsz > 0))
+}
@@ -0,0 +1,148 @@
+use core::fmt;
+use x86_64::VirtAddr;
+
+/// Size of the space reserved for the symbol table in the kernel image.
+pub const SYMBOL_TABLE_SIZE: usize = 256 * 1024;
+
+const MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+#[repr(C)]
+struct SymbolTable {
+    magic: [u8; 4],
+    data: [u8; SYMBOL_TABLE_SIZE - 4],
+}
+
+// filled after linking by tools/embed_symbols.py, the layout is described there.
+// Until then the magic does not match and no lookups succeed.
+#[used]
+#[link_section = ".ksyms"]
+static SYMBOL_TABLE: SymbolTable = SymbolTable {
+    magic: *b"NONE",
+    data: [0; SYMBOL_TABLE_SIZE - 4],
+};
+
+/// A function of the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: VirtAddr,
+    pub size: u64,
+}
+
+impl Symbol {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.address <= addr && addr.as_u64() - self.address.as_u64() < self.size
+    }
+}
+
+fn table() -> &'static [u8] {
+    let ptr: *const SymbolTable = &SYMBOL_TABLE;
+    // the table is patched into the kernel file after compiling, so the compiler
+    // must not assume it still holds the initial bytes.
+    let ptr = unsafe { core::ptr::read_volatile(&ptr) };
+    unsafe { core::slice::from_raw_parts(ptr as *const u8, SYMBOL_TABLE_SIZE) }
+}
+
+fn read_u32(table: &[u8], offset: usize) -> usize {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&table[offset..offset + 4]);
+    u32::from_le_bytes(bytes) as usize
+}
+
+fn read_u64(table: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&table[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Returns whether a symbol table was embedded into the kernel.
+pub fn is_available() -> bool {
+    table()[..4] == MAGIC
+}
+
+/// Returns the number of symbols in the table.
+pub fn count() -> usize {
+    if is_available() {
+        read_u32(table(), 4)
+    } else {
+        0
+    }
+}
+
+/// Returns the symbol at `index`, symbols are sorted by address.
+pub fn get(index: usize) -> Option<Symbol> {
+    if index >= count() {
+        return None;
+    }
+    let table = table();
+    let strings = read_u32(table, 8);
+    let strings_len = read_u32(table, 12);
+    let entry = HEADER_SIZE + index * ENTRY_SIZE;
+    let name_offset = read_u32(table, entry + 12);
+    let names = table.get(strings..strings + strings_len)?;
+    let name = names.get(name_offset..)?;
+    let end = name.iter().position(|&b| b == 0)?;
+    Some(Symbol {
+        name: core::str::from_utf8(&name[..end]).ok()?,
+        address: VirtAddr::new(read_u64(table, entry)),
+        size: read_u32(table, entry + 8) as u64,
+    })
+}
+
+/// Returns the function containing `addr` and the offset of `addr` in it.
+pub fn lookup(addr: VirtAddr) -> Option<(Symbol, u64)> {
+    // the last symbol that starts at or before `addr`.
+    let (mut low, mut high) = (0, count());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if get(mid)?.address <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let symbol = get(low.checked_sub(1)?)?;
+    if symbol.contains(addr) {
+        Some((symbol, addr - symbol.address))
+    } else {
+        None
+    }
+}
+
+/// Formats an address followed by `function+offset` if the symbol table
+/// contains it.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbolized {
+    addr: VirtAddr,
+    // return addresses can point past the end of a function that ends with a
+    // call, so they are looked up at the call instruction.
+    lookup_addr: VirtAddr,
+}
+
+impl Symbolized {
+    pub fn new(addr: VirtAddr) -> Self {
+        Symbolized {
+            addr,
+            lookup_addr: addr,
+        }
+    }
+
+    pub fn return_address(addr: VirtAddr) -> Self {
+        Symbolized {
+            addr,
+            lookup_addr: VirtAddr::new_truncate(addr.as_u64().saturating_sub(1)),
+        }
+    }
+}
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.addr.as_u64())?;
+        if let Some((symbol, _)) = lookup(self.lookup_addr) {
+            write!(f, " {}+{:#x}", symbol.name, self.addr - symbol.address)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use core::convert::TryFrom;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// The frequency of the clock that drives the PIT (programmable interval timer).
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+/// The timer interrupt frequency set by `crate::init`.
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+const CHANNEL_0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+// channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const COMMAND_RATE_GENERATOR: u8 = 0b0011_0100;
+const COMMAND_LATCH_CHANNEL_0: u8 = 0;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+// the reload value of channel 0, the firmware leaves it at the maximum (~18.2 Hz).
+static DIVISOR: AtomicU32 = AtomicU32::new(0x1_0000);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// elapsed cycles of the PIT clock, so that uptime stays exact when the divisor changes.
+static PIT_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Programs the PIT to raise the timer interrupt `frequency` times per second.
+///
+/// The PIT can only divide its clock by an integer, so the actual frequency is
+/// close to but not always exactly `frequency`, see `frequency`.
+pub fn init(frequency: u32) {
+    assert!(frequency > 0, "timer frequency must not be 0");
+    let divisor = ((PIT_FREQUENCY + frequency / 2) / frequency).clamp(1, 0x1_0000);
+    interrupts::without_interrupts(|| {
+        let mut command = Port::<u8>::new(COMMAND);
+        let mut channel = Port::<u8>::new(CHANNEL_0);
+        unsafe {
+            command.write(COMMAND_RATE_GENERATOR);
+            // a reload value of 0 stands for 0x10000.
+            channel.write(divisor as u8);
+            channel.write((divisor >> 8) as u8);
+        }
+        DIVISOR.store(divisor, Ordering::SeqCst);
+    });
+}
+
+/// Counts a timer interrupt, called by the timer interrupt handler.
+pub(crate) fn tick() {
+    PIT_CYCLES.fetch_add(
+        u64::from(DIVISOR.load(Ordering::Relaxed)),
+        Ordering::Relaxed,
+    );
+    TICKS.fetch_add(1, Ordering::Release);
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+/// Returns the timer interrupt frequency in Hz, rounded.
+pub fn frequency() -> u32 {
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    (PIT_FREQUENCY + divisor / 2) / divisor
+}
+
+/// Returns the time between two timer interrupts.
+pub fn tick_duration() -> Duration {
+    cycles_to_duration(u64::from(DIVISOR.load(Ordering::Relaxed)))
+}
+
+/// Returns the time since timer interrupts started, with the resolution of a tick.
+///
+/// It never goes backwards.
+pub fn uptime() -> Duration {
+    cycles_to_duration(PIT_CYCLES.load(Ordering::Relaxed))
+}
+
+fn cycles_to_duration(cycles: u64) -> Duration {
+    let nanos = u128::from(cycles) * NANOS_PER_SEC / u128::from(PIT_FREQUENCY);
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// Returns the number of ticks that last at least `duration`.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let period = tick_duration().as_nanos();
+    u64::try_from(duration.as_nanos().div_ceil(period)).unwrap_or(u64::MAX)
+}
+
+/// Halts the cpu until `ticks` timer interrupts arrived.
+///
+/// With interrupts disabled no timer interrupt can arrive, it waits with
+/// `busy_wait_ticks` instead.
+pub fn sleep_ticks(ticks: u64) {
+    if !interrupts::are_enabled() {
+        busy_wait_ticks(ticks);
+        return;
+    }
+    let end = self::ticks().saturating_add(ticks);
+    while self::ticks() < end {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Halts the cpu for at least `duration`, see `sleep_ticks`.
+pub fn sleep(duration: Duration) {
+    sleep_ticks(duration_to_ticks(duration));
+}
+
+// reads the current count of channel 0, it counts down from the divisor to 1
+// (0 stands for 0x10000).
+fn read_counter() -> u32 {
+    interrupts::without_interrupts(|| {
+        let mut command = Port::<u8>::new(COMMAND);
+        let mut channel = Port::<u8>::new(CHANNEL_0);
+        unsafe {
+            command.write(COMMAND_LATCH_CHANNEL_0);
+            let low = u32::from(channel.read());
+            let high = u32::from(channel.read());
+            match high << 8 | low {
+                0 => 0x1_0000,
+                count => count,
+            }
+        }
+    })
+}
+
+/// Spins for the length of `ticks` timer periods.
+///
+/// It polls the counter of the PIT instead of waiting for timer interrupts, so
+/// it also works with interrupts disabled. The PIT must have been set up by `init`.
+pub fn busy_wait_ticks(ticks: u64) {
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    let mut remaining = ticks.saturating_mul(u64::from(divisor));
+    let mut last = read_counter();
+    while remaining > 0 {
+        core::hint::spin_loop();
+        let now = read_counter();
+        // the counter reloads after reaching 1, polling is fast enough to not
+        // miss a whole period.
+        let elapsed = if now <= last {
+            last - now
+        } else {
+            last + divisor - now
+        };
+        remaining = remaining.saturating_sub(u64::from(elapsed));
+        last = now;
+    }
+}
+
+/// Spins for at least `duration`, see `busy_wait_ticks`.
+pub fn busy_wait(duration: Duration) {
+    busy_wait_ticks(duration_to_ticks(duration));
+}
+
+/// A point in time after which an operation gives up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    end: Duration,
+}
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Deadline {
+            end: uptime().checked_add(timeout).unwrap_or(Duration::MAX),
+        }
+    }
+
+    pub fn has_passed(&self) -> bool {
+        uptime() >= self.end
+    }
+
+    /// Returns the time left until the deadline, zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        let now = uptime();
+        if now >= self.end {
+            Duration::from_secs(0)
+        } else {
+            self.end - now
+        }
+    }
+}
+
+/// Formats the uptime as seconds with microseconds for log lines, like `[    1.000250]`.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp(pub Duration);
+
+/// Returns a timestamp of the current uptime.
+pub fn timestamp() -> Timestamp {
+    Timestamp(uptime())
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:>5}.{:06}]", self.0.as_secs(), self.0.subsec_micros())
+    }
+}
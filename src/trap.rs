@@ -0,0 +1,224 @@
+use core::arch::naked_asm;
+use core::fmt;
+use x86_64::structures::idt::{EntryOptions, InterruptDescriptorTable};
+use x86_64::VirtAddr;
+
+/// The registers of the interrupted code, saved by the exception entry stubs.
+///
+/// Handlers can change any register, the stub restores them from the frame
+/// before returning with `iretq`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// Pushed by the stub.
+    pub vector: u64,
+    /// Pushed by the cpu, or 0 by the stub for exceptions without error code.
+    pub error_code: u64,
+    // the interrupt stack frame pushed by the cpu.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn instruction_pointer(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.rip)
+    }
+
+    pub fn stack_pointer(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.rsp)
+    }
+
+    /// Returns whether the exception interrupted code running in ring 3.
+    pub fn is_user_mode(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            [("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx)],
+            [("rdx", self.rdx), ("rsi", self.rsi), ("rdi", self.rdi)],
+            [("rbp", self.rbp), ("rsp", self.rsp), ("r8", self.r8)],
+            [("r9", self.r9), ("r10", self.r10), ("r11", self.r11)],
+            [("r12", self.r12), ("r13", self.r13), ("r14", self.r14)],
+            [
+                ("r15", self.r15),
+                ("rip", self.rip),
+                ("rflags", self.rflags),
+            ],
+        ];
+        for row in rows.iter() {
+            for (i, (name, value)) in row.iter().enumerate() {
+                let separator = if i == 0 { "" } else { "  " };
+                write!(f, "{}{:>6} {:#018x}", separator, name, value)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "    cs {:#06x}  ss {:#06x}", self.cs, self.ss)
+    }
+}
+
+// pushes every general purpose register below the vector and error code, so
+// that the stack holds a TrapFrame, and calls the handler with a pointer to it.
+// The stack is 16 byte aligned at the call: the cpu aligns it before pushing
+// its 5 words and the stubs and this push 17 more.
+#[unsafe(naked)]
+unsafe extern "C" fn trap_entry() {
+    naked_asm!(
+        "cld",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {handler}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // the vector and the error code.
+        "add rsp, 16",
+        "iretq",
+        handler = sym crate::exceptions::dispatch,
+    );
+}
+
+macro_rules! trap_stub {
+    ($name:ident, $vector:literal) => {
+        #[unsafe(naked)]
+        unsafe extern "C" fn $name() {
+            naked_asm!(
+                "push 0",
+                concat!("push ", $vector),
+                "jmp {entry}",
+                entry = sym trap_entry,
+            );
+        }
+    };
+    // the cpu already pushed an error code.
+    ($name:ident, $vector:literal, error_code) => {
+        #[unsafe(naked)]
+        unsafe extern "C" fn $name() {
+            naked_asm!(
+                concat!("push ", $vector),
+                "jmp {entry}",
+                entry = sym trap_entry,
+            );
+        }
+    };
+}
+
+trap_stub!(divide_error_stub, 0);
+trap_stub!(debug_stub, 1);
+trap_stub!(nmi_stub, 2);
+trap_stub!(breakpoint_stub, 3);
+trap_stub!(overflow_stub, 4);
+trap_stub!(bound_range_stub, 5);
+trap_stub!(invalid_opcode_stub, 6);
+trap_stub!(device_not_available_stub, 7);
+trap_stub!(double_fault_stub, 8, error_code);
+trap_stub!(invalid_tss_stub, 10, error_code);
+trap_stub!(segment_not_present_stub, 11, error_code);
+trap_stub!(stack_segment_stub, 12, error_code);
+trap_stub!(general_protection_stub, 13, error_code);
+trap_stub!(page_fault_stub, 14, error_code);
+trap_stub!(x87_floating_point_stub, 16);
+trap_stub!(alignment_check_stub, 17, error_code);
+trap_stub!(machine_check_stub, 18);
+trap_stub!(simd_floating_point_stub, 19);
+trap_stub!(virtualization_stub, 20);
+trap_stub!(security_stub, 30, error_code);
+
+fn stub_addr(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// Points the exception entries at the stubs, which pass a `TrapFrame` to
+/// `exceptions::dispatch`.
+///
+/// Returns the options of the double fault entry so that the caller can give
+/// it its own stack.
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) -> &mut EntryOptions {
+    // the stubs match the entry types: the ones for exceptions with an error
+    // code expect it on the stack.
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(stub_addr(divide_error_stub));
+        idt.debug.set_handler_addr(stub_addr(debug_stub));
+        idt.non_maskable_interrupt
+            .set_handler_addr(stub_addr(nmi_stub));
+        idt.breakpoint.set_handler_addr(stub_addr(breakpoint_stub));
+        idt.overflow.set_handler_addr(stub_addr(overflow_stub));
+        idt.bound_range_exceeded
+            .set_handler_addr(stub_addr(bound_range_stub));
+        idt.invalid_opcode
+            .set_handler_addr(stub_addr(invalid_opcode_stub));
+        idt.device_not_available
+            .set_handler_addr(stub_addr(device_not_available_stub));
+        idt.invalid_tss
+            .set_handler_addr(stub_addr(invalid_tss_stub));
+        idt.segment_not_present
+            .set_handler_addr(stub_addr(segment_not_present_stub));
+        idt.stack_segment_fault
+            .set_handler_addr(stub_addr(stack_segment_stub));
+        idt.general_protection_fault
+            .set_handler_addr(stub_addr(general_protection_stub));
+        idt.x87_floating_point
+            .set_handler_addr(stub_addr(x87_floating_point_stub));
+        idt.alignment_check
+            .set_handler_addr(stub_addr(alignment_check_stub));
+        idt.machine_check
+            .set_handler_addr(stub_addr(machine_check_stub));
+        idt.simd_floating_point
+            .set_handler_addr(stub_addr(simd_floating_point_stub));
+        idt.virtualization
+            .set_handler_addr(stub_addr(virtualization_stub));
+        idt.security_exception
+            .set_handler_addr(stub_addr(security_stub));
+        idt.page_fault.set_handler_addr(stub_addr(page_fault_stub));
+
+        idt.double_fault
+            .set_handler_addr(stub_addr(double_fault_stub))
+    }
+}
@@ -0,0 +1,193 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::memory::{self, address_space, AddressSpace, BitmapFrameAllocator};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// level 4 entry 128, not used by the kernel.
+const USER_ADDR: u64 = 0x0000_4000_0000_0000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+fn kernel_translate(addr: VirtAddr) -> Option<x86_64::PhysAddr> {
+    memory::with_kernel_memory(|m| m.mapper.translate_addr(addr)).unwrap()
+}
+
+#[test_case]
+fn kernel_ranges_are_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    let kernel_code = VirtAddr::new(free_frames as *const () as u64);
+    assert!(!space.is_user_range(kernel_code, 4096));
+    assert!(space
+        .map(kernel_code, 4096, PageTableFlags::WRITABLE)
+        .is_err());
+    assert!(space.is_user_range(VirtAddr::new(USER_ADDR), 4096));
+}
+
+#[test_case]
+fn user_mappings_are_private() {
+    let mut space = AddressSpace::new().unwrap();
+    let addr = VirtAddr::new(USER_ADDR);
+    space.map(addr, 2 * 4096, PageTableFlags::WRITABLE).unwrap();
+
+    let (_, flags) = space.translate(addr + 4096u64).unwrap();
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+    assert_eq!(kernel_translate(addr), None);
+
+    unsafe { space.activate() };
+    assert!(space.is_active());
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    // the kernel heap is still mapped.
+    let boxed = Box::new(7);
+    assert_eq!(*boxed, 7);
+    unsafe { address_space::activate_kernel() };
+    assert!(!space.is_active());
+}
+
+#[test_case]
+fn protect_and_unmap() {
+    let mut space = AddressSpace::new().unwrap();
+    let addr = VirtAddr::new(USER_ADDR);
+    space.map(addr, 4096, PageTableFlags::WRITABLE).unwrap();
+
+    space
+        .protect(addr, 4096, PageTableFlags::NO_EXECUTE)
+        .unwrap();
+    let (_, flags) = space.translate(addr).unwrap();
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+
+    let free_before = free_frames();
+    space.unmap(addr, 4096).unwrap();
+    assert_eq!(space.translate(addr), None);
+    assert_eq!(free_frames(), free_before + 1);
+}
+
+#[test_case]
+fn drop_frees_all_frames() {
+    let free_before = free_frames();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        space
+            .map(VirtAddr::new(USER_ADDR), 8 * 4096, PageTableFlags::WRITABLE)
+            .unwrap();
+        unsafe { space.activate() };
+        assert!(free_frames() < free_before);
+    }
+    // dropping the active address space switches back to the kernel tables.
+    assert_eq!(free_frames(), free_before);
+    assert!(kernel_translate(VirtAddr::new(USER_ADDR)).is_none());
+}
+
+fn ref_count(phys: x86_64::PhysAddr) -> usize {
+    let frame = x86_64::structures::paging::PhysFrame::containing_address(phys);
+    memory::with_kernel_memory(|m| m.frame_allocator.ref_count(frame)).unwrap()
+}
+
+#[test_case]
+fn clone_cow_shares_frames() {
+    let mut parent = AddressSpace::new().unwrap();
+    let addr = VirtAddr::new(USER_ADDR);
+    parent.map(addr, 4096, PageTableFlags::WRITABLE).unwrap();
+
+    let child = parent.clone_cow().unwrap();
+    let (parent_phys, parent_flags) = parent.translate(addr).unwrap();
+    let (child_phys, child_flags) = child.translate(addr).unwrap();
+    assert_eq!(parent_phys, child_phys);
+    for flags in [parent_flags, child_flags].iter() {
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        assert!(flags.contains(address_space::COPY_ON_WRITE));
+    }
+    assert_eq!(ref_count(parent_phys), 2);
+
+    drop(child);
+    assert_eq!(ref_count(parent_phys), 1);
+}
+
+#[test_case]
+fn writes_copy_shared_frames() {
+    let mut parent = AddressSpace::new().unwrap();
+    let addr = VirtAddr::new(USER_ADDR);
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    parent.map(addr, 4096, PageTableFlags::WRITABLE).unwrap();
+    unsafe {
+        parent.activate();
+        ptr.write_volatile(42);
+    }
+
+    let child = parent.clone_cow().unwrap();
+    unsafe {
+        child.activate();
+        assert_eq!(ptr.read_volatile(), 42);
+        ptr.write_volatile(7);
+        assert_eq!(ptr.read_volatile(), 7);
+        parent.activate();
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    let (parent_phys, _) = parent.translate(addr).unwrap();
+    let (child_phys, child_flags) = child.translate(addr).unwrap();
+    assert_ne!(parent_phys, child_phys);
+    assert!(child_flags.contains(PageTableFlags::WRITABLE));
+    assert_eq!(ref_count(parent_phys), 1);
+
+    // the parent is the only user of its frame now, so it is not copied again.
+    unsafe { ptr.write_volatile(43) };
+    assert_eq!(parent.translate(addr).unwrap().0, parent_phys);
+    unsafe { address_space::activate_kernel() };
+}
+
+#[test_case]
+fn cow_clones_free_all_frames() {
+    let free_before = free_frames();
+    {
+        let mut parent = AddressSpace::new().unwrap();
+        let addr = VirtAddr::new(USER_ADDR);
+        parent
+            .map(addr, 4 * 4096, PageTableFlags::WRITABLE)
+            .unwrap();
+        let child = parent.clone_cow().unwrap();
+        unsafe {
+            child.activate();
+            addr.as_mut_ptr::<u64>().write_volatile(1);
+            address_space::activate_kernel();
+        }
+    }
+    assert_eq!(free_frames(), free_before);
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::apic::ApicError;
+use rust_os::interrupts::{self, InterruptController, InterruptIndex};
+use rust_os::memory::{self, BitmapFrameAllocator};
+use rust_os::{acpi, apic};
+use x86_64::instructions::port::Port;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    assert_eq!(interrupts::controller(), InterruptController::Pic);
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    // a switch that fails while routing leaves the PIC in use.
+    let failed = unsafe { apic::init(|_| Err(ApicError::NoRoute(u32::MAX))) };
+    assert!(matches!(failed, Err(ApicError::NoRoute(_))));
+    assert_eq!(interrupts::controller(), InterruptController::Pic);
+    interrupts::init_apic().expect("failed to switch to the APIC");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_lists_the_controllers() {
+    let madt = acpi::find_madt().unwrap();
+    assert!(madt.processors >= 1);
+    assert!(!madt.local_apic_address.is_null());
+    assert!(madt.io_apics().next().is_some());
+    assert_eq!(
+        apic::madt().unwrap().local_apic_address,
+        madt.local_apic_address
+    );
+}
+
+#[test_case]
+fn apic_replaced_the_pic() {
+    assert_eq!(interrupts::controller(), InterruptController::Apic);
+    assert!(apic::is_enabled());
+    // every line of both PICs is masked.
+    let (master, slave) = unsafe { (Port::<u8>::new(0x21).read(), Port::<u8>::new(0xa1).read()) };
+    assert_eq!((master, slave), (0xff, 0xff));
+    assert!(unsafe { apic::init(|_| Ok(())) }.is_err());
+}
+
+#[test_case]
+fn timer_and_keyboard_are_routed() {
+    for &index in [InterruptIndex::Timer, InterruptIndex::Keyboard].iter() {
+        let (gsi, _) = apic::isa_irq_to_gsi(index.irq()).unwrap();
+        assert!(!apic::is_gsi_masked(gsi).unwrap());
+        assert_eq!(apic::gsi_vector(gsi).unwrap(), index as u8);
+    }
+}
+
+#[test_case]
+fn timer_interrupts_arrive() {
+    // hlt only returns when an interrupt was delivered, the timer handler
+    // must also have acknowledged it for the second one to arrive.
+    x86_64::instructions::hlt();
+    x86_64::instructions::hlt();
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::backtrace::{self, Frame, FrameWalker};
+use rust_os::memory;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn outer(frames: &mut [Option<Frame>; 8]) {
+    middle(frames);
+    // keeps the call from becoming a tail call.
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+#[inline(never)]
+fn middle(frames: &mut [Option<Frame>; 8]) {
+    inner(frames);
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+#[inline(never)]
+fn inner(frames: &mut [Option<Frame>; 8]) {
+    for (slot, frame) in frames.iter_mut().zip(backtrace::walk()) {
+        *slot = Some(frame);
+    }
+}
+
+#[test_case]
+fn walk_finds_the_callers() {
+    let mut frames = [None; 8];
+    outer(&mut frames);
+
+    // the first frames return into middle and outer.
+    let returns_into = |frame: Option<Frame>, function: usize| {
+        let addr = frame.unwrap().return_address.as_u64() as usize;
+        addr > function && addr < function + 0x200
+    };
+    assert!(returns_into(frames[0], middle as *const () as usize));
+    assert!(returns_into(frames[1], outer as *const () as usize));
+    for pair in frames.windows(2) {
+        if let [Some(callee), Some(caller)] = pair {
+            assert!(callee.frame_pointer < caller.frame_pointer);
+        }
+    }
+}
+
+#[test_case]
+fn walk_stops_at_unmapped_frames() {
+    // a fake frame whose saved frame pointer points to unmapped memory.
+    let fake_frame: [u64; 2] = [0xdead_beef_0000, 0x20_1000];
+    let frames = FrameWalker::new(fake_frame.as_ptr() as u64);
+    let mut count = 0;
+    for frame in frames {
+        assert_eq!(frame.return_address, VirtAddr::new(0x20_1000));
+        count += 1;
+    }
+    assert_eq!(count, 1);
+}
+
+#[test_case]
+fn walk_stops_at_invalid_frame_pointers() {
+    assert_eq!(FrameWalker::new(0).count(), 0);
+    assert_eq!(FrameWalker::new(0x1001).count(), 0);
+    assert_eq!(FrameWalker::new(0x0000_8000_0000_0000).count(), 0);
+    backtrace::print_backtrace();
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::buddy::{self, MAX_ORDER};
+use rust_os::memory::BuddyFrameAllocator;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn blocks_are_aligned_to_their_size() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    for order in 0..=MAX_ORDER {
+        let block = allocator.allocate(order).expect("out of memory");
+        let size = 4096u64 << order;
+        assert_eq!(block.start_address().as_u64() % size, 0);
+        unsafe { allocator.deallocate(block, order) };
+    }
+}
+
+#[test_case]
+fn freed_blocks_are_merged() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+    let largest_before = allocator.free_blocks(MAX_ORDER);
+
+    let mut frames = [None::<PhysFrame>; 16];
+    for slot in frames.iter_mut() {
+        *slot = allocator.allocate(0);
+    }
+    assert_eq!(allocator.free_frames(), free_before - frames.len());
+    for frame in frames.iter().flatten() {
+        unsafe { allocator.deallocate(*frame, 0) };
+    }
+
+    assert_eq!(allocator.free_frames(), free_before);
+    assert_eq!(allocator.free_blocks(MAX_ORDER), largest_before);
+}
+
+#[test_case]
+fn contiguous_allocation_rounds_up() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+
+    let frame = allocator.allocate_contiguous(3).expect("out of memory");
+    assert_eq!(buddy::order_for(3), 2);
+    assert_eq!(allocator.free_frames(), free_before - 4);
+    unsafe { allocator.deallocate(frame, buddy::order_for(3)) };
+    assert_eq!(allocator.free_frames(), free_before);
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().expect("out of memory");
+    assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+    unsafe { allocator.deallocate_frame(frame) };
+}
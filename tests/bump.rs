@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::ptr;
+use rust_os::allocator::bump::{Arena, BumpAllocator};
+use rust_os::allocator::Locked;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn bump_allocator_resets_when_empty() {
+    static mut REGION: [u8; 256] = [0; 256];
+
+    let bump = Locked::new(BumpAllocator::new());
+    unsafe { bump.lock().init(ptr::addr_of_mut!(REGION) as usize, 256) };
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let a = unsafe { bump.alloc(layout) };
+    let b = unsafe { bump.alloc(layout) };
+    assert!(!a.is_null() && !b.is_null() && a != b);
+    assert_eq!(bump.lock().used(), 128);
+
+    unsafe {
+        bump.dealloc(a, layout);
+        bump.dealloc(b, layout);
+    }
+    assert_eq!(bump.lock().used(), 0);
+    assert_eq!(unsafe { bump.alloc(layout) }, a);
+}
+
+#[test_case]
+fn arena_allocates_until_full() {
+    let mut buffer = [MaybeUninit::uninit(); 64];
+    let mut arena = Arena::new(&mut buffer);
+
+    let x = arena.alloc(7u64).ok().unwrap();
+    let values = arena.alloc_slice_copy(&[1u32, 2, 3]).unwrap();
+    assert_eq!(*x, 7);
+    assert_eq!(values, &[1, 2, 3]);
+    assert!(arena.alloc_slice_fill(64, 0u8).is_none());
+
+    arena.reset();
+    assert_eq!(arena.used(), 0);
+    assert!(arena.alloc_slice_fill(64, 0u8).is_some());
+}
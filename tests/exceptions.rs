@@ -0,0 +1,137 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use rust_os::exceptions::{
+    self, DescriptorTable, Exception, ExceptionInfo, Recovery, SelectorErrorCode,
+};
+use rust_os::memory;
+use rust_os::trap::TrapFrame;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// the vector and error code the hook saw, and the length of the instruction it skips.
+static VECTOR: AtomicU8 = AtomicU8::new(0xff);
+static ERROR_CODE: AtomicU64 = AtomicU64::new(u64::MAX);
+static SKIP: AtomicU64 = AtomicU64::new(0);
+static FIRST_BYTE: AtomicU8 = AtomicU8::new(0);
+static SAVED_RAX: AtomicU64 = AtomicU64::new(0);
+
+fn skipping_hook(info: &ExceptionInfo, frame: &mut TrapFrame) -> Recovery {
+    VECTOR.store(info.exception.vector(), Ordering::SeqCst);
+    ERROR_CODE.store(info.error_code.unwrap_or(u64::MAX), Ordering::SeqCst);
+    assert_eq!(frame.vector, u64::from(info.exception.vector()));
+    let bytes = exceptions::instruction_bytes(frame.instruction_pointer()).unwrap();
+    FIRST_BYTE.store(bytes[0], Ordering::SeqCst);
+    frame.rip += SKIP.load(Ordering::SeqCst);
+    // the registers the register test passes in and expects back.
+    SAVED_RAX.store(frame.rax, Ordering::SeqCst);
+    frame.r12 = frame.r12.wrapping_mul(6);
+    Recovery::Resume
+}
+
+// runs `f` with the hook installed, it skips `skip` bytes at the fault.
+fn with_skipping_hook(skip: u64, f: impl FnOnce()) {
+    VECTOR.store(0xff, Ordering::SeqCst);
+    ERROR_CODE.store(u64::MAX, Ordering::SeqCst);
+    SKIP.store(skip, Ordering::SeqCst);
+    let previous = exceptions::set_hook(Some(skipping_hook));
+    f();
+    exceptions::set_hook(previous);
+}
+
+#[test_case]
+fn invalid_opcode_is_recovered() {
+    with_skipping_hook(2, || unsafe { asm!("ud2") });
+    assert_eq!(
+        VECTOR.load(Ordering::SeqCst),
+        Exception::InvalidOpcode.vector()
+    );
+    assert_eq!(ERROR_CODE.load(Ordering::SeqCst), u64::MAX);
+    assert_eq!(FIRST_BYTE.load(Ordering::SeqCst), 0x0f);
+}
+
+#[test_case]
+fn registers_are_saved_and_restored() {
+    let mut r12: u64 = 7;
+    with_skipping_hook(2, || unsafe {
+        asm!("ud2", in("rax") 0x1234_5678u64, inout("r12") r12)
+    });
+    assert_eq!(SAVED_RAX.load(Ordering::SeqCst), 0x1234_5678);
+    assert_eq!(r12, 42);
+    // 15 registers, vector, error code and the 5 words the cpu pushes.
+    assert_eq!(core::mem::size_of::<TrapFrame>(), 22 * 8);
+}
+
+#[test_case]
+fn divide_error_is_recovered() {
+    // `div rcx` is encoded in 3 bytes.
+    with_skipping_hook(3, || unsafe {
+        asm!(
+            "div rcx",
+            in("rcx") 0u64,
+            inout("rax") 1u64 => _,
+            inout("rdx") 0u64 => _,
+        )
+    });
+    assert_eq!(
+        VECTOR.load(Ordering::SeqCst),
+        Exception::DivideError.vector()
+    );
+}
+
+#[test_case]
+fn general_protection_fault_reports_the_selector() {
+    // loading a selector past the end of the GDT, `mov ds, ax` has 2 bytes.
+    let selector: u64 = 0x1238;
+    with_skipping_hook(2, || unsafe {
+        asm!("mov ds, ax", in("ax") selector as u16)
+    });
+    assert_eq!(
+        VECTOR.load(Ordering::SeqCst),
+        Exception::GeneralProtectionFault.vector()
+    );
+    let code = SelectorErrorCode(ERROR_CODE.load(Ordering::SeqCst));
+    assert_eq!(code.table(), DescriptorTable::Gdt);
+    assert_eq!(code.index(), selector >> 3);
+    assert!(!code.is_external());
+}
+
+#[test_case]
+fn selector_error_codes_are_decoded() {
+    let code = SelectorErrorCode(13 << 3 | 0b011);
+    assert_eq!(code.table(), DescriptorTable::Idt);
+    assert_eq!(code.index(), 13);
+    assert!(code.is_external());
+    assert_eq!(SelectorErrorCode(0b100).table(), DescriptorTable::Ldt);
+    assert!(Exception::StackSegmentFault.has_selector_error_code());
+    assert!(!Exception::AlignmentCheck.has_selector_error_code());
+}
+
+#[test_case]
+fn unmapped_instructions_are_not_read() {
+    assert!(exceptions::instruction_bytes(VirtAddr::new(0xdead_0000_0000)).is_none());
+    let code = VirtAddr::new(skipping_hook as *const () as u64);
+    assert!(exceptions::instruction_bytes(code).is_some());
+}
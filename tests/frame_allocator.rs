@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::BitmapFrameAllocator;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// the test cases don't get any arguments, so the allocator is shared through a static.
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocate_and_free_updates_counts() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+
+    let frame = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(allocator.free_frames(), free_before - 1);
+    assert_eq!(
+        allocator.used_frames() + allocator.free_frames(),
+        allocator.total_frames()
+    );
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free_before);
+}
+
+#[test_case]
+fn allocated_frames_are_distinct() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let mut frames = [None::<PhysFrame>; 64];
+    for i in 0..frames.len() {
+        let frame = allocator.allocate_frame().expect("out of frames");
+        assert!(!frames[..i].contains(&Some(frame)));
+        frames[i] = Some(frame);
+    }
+    for frame in frames.iter().flatten() {
+        unsafe { allocator.deallocate_frame(*frame) };
+    }
+}
+
+#[test_case]
+fn freed_frames_are_reused() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let frame = allocator.allocate_frame().expect("out of frames");
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+    unsafe { allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn shared_frames_are_freed_with_the_last_reference() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+
+    let frame = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(allocator.ref_count(frame), 1);
+    allocator.share_frame(frame);
+    assert_eq!(allocator.ref_count(frame), 2);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.ref_count(frame), 1);
+    assert_eq!(allocator.free_frames(), free_before - 1);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.ref_count(frame), 0);
+    assert_eq!(allocator.free_frames(), free_before);
+}
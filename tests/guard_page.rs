@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rust_os::memory::{self, stack, BitmapFrameAllocator};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("guard_page::stack_overflow_hits_guard_page...\t");
+
+    rust_os::gdt::init();
+    init_test_idt();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    let stack = stack::allocate_stack("test stack", 4 * 4096).expect("stack allocation failed");
+    let top: *mut u64 = (stack.top() - 8u64).as_mut_ptr();
+    unsafe { top.write_volatile(1) };
+
+    // the first byte below the stack is in the guard page.
+    let below: *mut u8 = (stack.bottom() - 1u64).as_mut_ptr();
+    unsafe { below.write_volatile(1) };
+
+    serial_println!("[no page fault]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    match stack::guard_page_owner(Cr2::read()) {
+        Some("test stack") => {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        }
+        owner => {
+            serial_println!("[failed]\nguard page owner: {:?}", owner);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+    loop {}
+}
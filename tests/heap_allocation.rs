@@ -6,23 +6,26 @@
 
 extern crate alloc;
 
+use alloc::alloc::{alloc, dealloc, Layout};
 use alloc::{boxed::Box, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use rust_os::allocator::HEAP_SIZE;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::allocator::{self, oom, HEAP_SIZE};
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use rust_os::allocator;
-    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use rust_os::memory::{self, BitmapFrameAllocator};
     use x86_64::VirtAddr;
 
     rust_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
 
     test_main();
     loop {}
@@ -65,3 +68,106 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+// This test allocates more memory than is mapped by init_heap, so it only
+// succeeds if the heap maps more pages on demand.
+#[test_case]
+fn heap_grows_on_demand() {
+    let n = 4 * HEAP_SIZE;
+    let mut vec = Vec::with_capacity(n);
+    for i in 0..n {
+        vec.push(i as u8);
+    }
+    assert!(allocator::heap_size() > HEAP_SIZE);
+    assert_eq!(vec[n - 1], (n - 1) as u8);
+}
+
+#[test_case]
+fn stats_count_allocations() {
+    let before = allocator::stats();
+    let x = Box::new([0u8; 100]);
+    let during = allocator::stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 100);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+    drop(x);
+    assert_eq!(allocator::stats().bytes_in_use, before.bytes_in_use);
+}
+
+#[test_case]
+fn freed_allocations_are_not_leaks() {
+    allocator::stats::assert_no_leaks(|| {
+        let mut vec = Vec::new();
+        for i in 0..100 {
+            vec.push(Box::new(i));
+        }
+    });
+}
+
+#[test_case]
+fn leaked_allocations_are_reported() {
+    let report = allocator::stats::leak_check(|| {
+        Box::leak(Box::new(7u32));
+    });
+    assert_eq!(report.leaked_allocations, 1);
+    assert_eq!(report.leaked_bytes, 4);
+}
+
+const CHUNK: usize = 4096;
+static CHUNKS: spin::Mutex<[usize; 4096]> = spin::Mutex::new([0; 4096]);
+
+// a shrinker that gives back everything filled_heap allocated.
+fn free_chunks(_layout: Layout) -> usize {
+    let mut chunks = CHUNKS.lock();
+    let mut freed = 0;
+    for chunk in chunks.iter_mut().filter(|c| **c != 0) {
+        let layout = Layout::from_size_align(CHUNK, 8).unwrap();
+        unsafe { dealloc(*chunk as *mut u8, layout) };
+        *chunk = 0;
+        freed += CHUNK;
+    }
+    freed
+}
+
+#[test_case]
+fn shrinkers_run_when_heap_is_full() {
+    // stop the heap from growing and use up all of its memory.
+    allocator::set_heap_limit(allocator::heap_size());
+    for chunk in CHUNKS.lock().iter_mut() {
+        let ptr = unsafe { alloc(Layout::from_size_align(CHUNK, 8).unwrap()) };
+        if ptr.is_null() {
+            break;
+        }
+        *chunk = ptr as usize;
+    }
+
+    let shrinker = oom::register_shrinker("test chunks", free_chunks).unwrap();
+    let layout = Layout::from_size_align(CHUNK / 2, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null());
+    assert!(CHUNKS.lock().iter().all(|c| *c == 0));
+
+    unsafe { dealloc(ptr, layout) };
+    oom::unregister_shrinker(shrinker);
+    allocator::set_heap_limit(allocator::HEAP_MAX_SIZE);
+}
+
+static LYING_SHRINKER_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+// claims to free memory without freeing anything.
+fn lying_shrinker(_layout: Layout) -> usize {
+    LYING_SHRINKER_CALLS.fetch_add(1, Ordering::Relaxed);
+    CHUNK
+}
+
+#[test_case]
+fn reclaim_gives_up_when_shrinkers_never_help() {
+    let shrinker = oom::register_shrinker("lying", lying_shrinker).unwrap();
+    // larger than the heap can ever grow.
+    let layout = Layout::from_size_align(2 * allocator::HEAP_MAX_SIZE, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(ptr.is_null());
+    let calls = LYING_SHRINKER_CALLS.load(Ordering::Relaxed);
+    assert!(calls > 0 && calls <= 16);
+    oom::unregister_shrinker(shrinker);
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::arch::x86_64::_rdtsc;
+use core::panic::PanicInfo;
+use rust_os::serial_print;
+
+entry_point!(main);
+
+// Compare the global allocators by running this test with and without
+// `--features fixed_size_block`. The numbers are TSC cycles per operation.
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    if cfg!(feature = "fixed_size_block") {
+        rust_os::serial_println!("global allocator: fixed size block");
+    } else {
+        rust_os::serial_println!("global allocator: linked list");
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const ITERATIONS: u64 = 10_000;
+
+fn report(cycles: u64) {
+    serial_print!("{} cycles/op ", cycles / ITERATIONS);
+}
+
+#[test_case]
+fn small_boxes() {
+    let start = unsafe { _rdtsc() };
+    for i in 0..ITERATIONS {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    report(unsafe { _rdtsc() } - start);
+}
+
+#[test_case]
+fn fragmented_heap() {
+    // keep every other allocation alive so the free memory is scattered.
+    let mut kept = Vec::new();
+    for i in 0..1000 {
+        let x = Box::new([i as u8; 48]);
+        if i % 2 == 0 {
+            kept.push(x);
+        }
+    }
+
+    let start = unsafe { _rdtsc() };
+    for i in 0..ITERATIONS {
+        let x = Box::new([i as u8; 48]);
+        assert_eq!(x[0], i as u8);
+    }
+    report(unsafe { _rdtsc() } - start);
+}
+
+#[test_case]
+fn mixed_sizes() {
+    let start = unsafe { _rdtsc() };
+    for i in 0..ITERATIONS {
+        let v: Vec<u8> = Vec::with_capacity(8 << (i % 9));
+        assert!(v.capacity() >= 8);
+    }
+    report(unsafe { _rdtsc() } - start);
+}
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::panic::PanicInfo;
+use rust_os::allocator::bump::BumpAllocator;
+use rust_os::allocator::debug::RedZoneAllocator;
+use rust_os::allocator::Locked;
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+// the red zone allocator is tested on its own region, so the test doesn't depend
+// on the heap_debug feature.
+static mut REGION: [u8; 4096] = [0; 4096];
+static ALLOCATOR: RedZoneAllocator<Locked<BumpAllocator>> =
+    RedZoneAllocator::new(Locked::new(BumpAllocator::new()));
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("heap_corruption::overflow_is_detected...\t");
+    unsafe {
+        ALLOCATOR
+            .inner()
+            .lock()
+            .init(core::ptr::addr_of_mut!(REGION) as usize, 4096);
+    }
+
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe {
+        let ptr = ALLOCATOR.alloc(layout);
+        // write one byte past the end of the allocation.
+        ptr.add(32).write_volatile(0);
+        ALLOCATOR.dealloc(ptr, layout);
+    }
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,140 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_os::irq::{self, IrqError, IrqReturn, MAX_HANDLERS_PER_IRQ};
+use x86_64::instructions::port::Port;
+
+// nothing in QEMU raises IRQ 5, the tests raise its vector 37 with `int`.
+const TEST_IRQ: u8 = 5;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+// sum of the contexts handlers were called with.
+static CONTEXTS: AtomicUsize = AtomicUsize::new(0);
+
+fn claiming_handler(irq: u8, context: usize) -> IrqReturn {
+    assert_eq!(irq, TEST_IRQ);
+    CALLS.fetch_add(1, Ordering::SeqCst);
+    CONTEXTS.fetch_add(context, Ordering::SeqCst);
+    IrqReturn::Handled
+}
+
+fn other_device_handler(_irq: u8, _context: usize) -> IrqReturn {
+    CALLS.fetch_add(1, Ordering::SeqCst);
+    IrqReturn::NotMine
+}
+
+fn raise_test_irq() {
+    assert_eq!(irq::vector(TEST_IRQ), 37);
+    unsafe { asm!("int 37") };
+}
+
+fn test_irq_masked() -> bool {
+    let mask = unsafe { Port::<u8>::new(0x21).read() };
+    mask & (1 << TEST_IRQ) != 0
+}
+
+#[test_case]
+fn timer_and_keyboard_are_registered() {
+    assert_eq!(irq::handler_count(0), 1);
+    assert_eq!(irq::handler_count(1), 1);
+    let before = irq::count(0);
+    x86_64::instructions::hlt();
+    x86_64::instructions::hlt();
+    assert!(irq::count(0) > before);
+}
+
+#[test_case]
+fn shared_line_calls_every_handler() {
+    CALLS.store(0, Ordering::SeqCst);
+    CONTEXTS.store(0, Ordering::SeqCst);
+    let first = irq::register(TEST_IRQ, claiming_handler, 1).unwrap();
+    let second = irq::register(TEST_IRQ, claiming_handler, 10).unwrap();
+    assert_eq!(irq::handler_count(TEST_IRQ), 2);
+    assert!(!test_irq_masked());
+
+    let count = irq::count(TEST_IRQ);
+    raise_test_irq();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    assert_eq!(CONTEXTS.load(Ordering::SeqCst), 11);
+    assert_eq!(irq::count(TEST_IRQ), count + 1);
+
+    irq::unregister(first).unwrap();
+    raise_test_irq();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+    assert_eq!(CONTEXTS.load(Ordering::SeqCst), 21);
+
+    irq::unregister(second).unwrap();
+    assert_eq!(irq::handler_count(TEST_IRQ), 0);
+    assert!(test_irq_masked());
+}
+
+#[test_case]
+fn unclaimed_interrupts_are_counted() {
+    CALLS.store(0, Ordering::SeqCst);
+    let handle = irq::register(TEST_IRQ, other_device_handler, 0).unwrap();
+    let unhandled = irq::unhandled_count(TEST_IRQ);
+    raise_test_irq();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    assert_eq!(irq::unhandled_count(TEST_IRQ), unhandled + 1);
+    irq::unregister(handle).unwrap();
+}
+
+#[test_case]
+fn stale_handles_are_rejected() {
+    let handle = irq::register(TEST_IRQ, claiming_handler, 0).unwrap();
+    irq::unregister(handle).unwrap();
+    // the slot is reused by a new registration.
+    let other = irq::register(TEST_IRQ, claiming_handler, 0).unwrap();
+    assert!(matches!(
+        irq::unregister(handle),
+        Err(IrqError::NotRegistered)
+    ));
+    assert_eq!(irq::handler_count(TEST_IRQ), 1);
+    irq::unregister(other).unwrap();
+}
+
+#[test_case]
+fn lines_have_limits() {
+    assert!(matches!(
+        irq::register(irq::IRQ_COUNT as u8, claiming_handler, 0),
+        Err(IrqError::InvalidIrq(_))
+    ));
+    // the PIC has no lines above 15.
+    assert!(matches!(
+        irq::register(16, claiming_handler, 0),
+        Err(IrqError::NoPicLine(16))
+    ));
+    assert_eq!(irq::handler_count(16), 0);
+
+    let mut handles = [None; MAX_HANDLERS_PER_IRQ];
+    for handle in handles.iter_mut() {
+        *handle = Some(irq::register(TEST_IRQ, claiming_handler, 0).unwrap());
+    }
+    assert!(matches!(
+        irq::register(TEST_IRQ, claiming_handler, 0),
+        Err(IrqError::LineFull(TEST_IRQ))
+    ));
+    for handle in handles.iter() {
+        irq::unregister(handle.unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::MemoryRegionType;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, report, BitmapFrameAllocator};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    assert!(report::report().is_none());
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    report::init(&boot_info.memory_map);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn summaries_cover_the_memory_map() {
+    let report = report::report().unwrap();
+    let mut bytes = 0;
+    for region in report.memory_map().iter() {
+        bytes += region.range.end_addr() - region.range.start_addr();
+    }
+    assert_eq!(report.total_bytes(), bytes);
+    assert!(report.bytes(MemoryRegionType::Usable) > 0);
+    assert!(report.bytes(MemoryRegionType::Kernel) > 0);
+    let regions: usize = report.summaries().map(|summary| summary.regions).sum();
+    assert_eq!(regions, report.memory_map().iter().count());
+}
+
+#[test_case]
+fn usable_memory_backs_the_frame_allocator() {
+    let report = report::report().unwrap();
+    let total_frames = report.total_frames().unwrap() as u64;
+    assert_eq!(total_frames * 4096, report.bytes(MemoryRegionType::Usable));
+    assert!(report.free_frames().unwrap() as u64 <= total_frames);
+}
+
+#[test_case]
+fn report_tracks_free_frames() {
+    let free_before = report::report().unwrap().free_frames().unwrap();
+    let frame = memory::with_kernel_memory(|m| m.frame_allocator.allocate_frame())
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        report::report().unwrap().free_frames(),
+        Some(free_before - 1)
+    );
+    memory::with_kernel_memory(|m| unsafe { m.frame_allocator.deallocate_frame(frame) });
+    report::print_report();
+}
@@ -0,0 +1,179 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, BuddyFrameAllocator, MappedPageSize};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size2MiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+struct Paging {
+    physical_memory_offset: VirtAddr,
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BuddyFrameAllocator,
+}
+
+static PAGING: Mutex<Option<Paging>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(physical_memory_offset) };
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, physical_memory_offset) };
+    *PAGING.lock() = Some(Paging {
+        physical_memory_offset,
+        mapper,
+        frame_allocator,
+    });
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn translate_identity_mapped_vga_buffer() {
+    let offset = PAGING.lock().as_ref().unwrap().physical_memory_offset;
+    let translation = unsafe { memory::translate(VirtAddr::new(0xb8000), offset) };
+    assert_eq!(
+        translation.map(|t| t.phys_addr),
+        Some(PhysAddr::new(0xb8000))
+    );
+}
+
+#[test_case]
+fn translate_physical_memory_mapping() {
+    // the bootloader maps the physical memory with huge pages.
+    let offset = PAGING.lock().as_ref().unwrap().physical_memory_offset;
+    let phys = unsafe { memory::translate_addr(offset + 0x1234u64, offset) };
+    assert_eq!(phys, Some(PhysAddr::new(0x1234)));
+}
+
+#[test_case]
+fn translate_unmapped_address() {
+    let offset = PAGING.lock().as_ref().unwrap().physical_memory_offset;
+    let translation = unsafe { memory::translate(VirtAddr::new(0xdead_beef_0000), offset) };
+    assert_eq!(translation, None);
+}
+
+#[test_case]
+fn map_and_translate_2mib_page() {
+    let mut guard = PAGING.lock();
+    let paging = guard.as_mut().unwrap();
+
+    let page: Page<Size2MiB> = Page::containing_address(VirtAddr::new(0x5555_0000_0000));
+    let frame: PhysFrame<Size2MiB> = paging.frame_allocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        memory::map_2mib_page(
+            page,
+            frame,
+            flags,
+            &mut paging.mapper,
+            &mut paging.frame_allocator,
+        )
+        .expect("map_2mib_page failed");
+    }
+
+    let addr = page.start_address() + 0x1_2345u64;
+    let translation = unsafe { memory::translate(addr, paging.physical_memory_offset) }.unwrap();
+    assert_eq!(translation.phys_addr, frame.start_address() + 0x1_2345u64);
+    assert_eq!(translation.page_size, MappedPageSize::Size2MiB);
+
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+}
+
+#[test_case]
+fn dump_finds_vga_buffer() {
+    use rust_os::memory::dump;
+
+    let vga = VirtAddr::new(0xb8000);
+    let mut found = None;
+    assert!(dump::for_each_active_range(|range| {
+        if range.virt_start <= vga && vga.as_u64() < range.virt_start.as_u64() + range.size {
+            found = Some(*range);
+        }
+    }));
+    let range = found.expect("vga buffer not mapped");
+    let offset = vga - range.virt_start;
+    assert_eq!(range.phys_start + offset, PhysAddr::new(0xb8000));
+    assert!(range.is_writable());
+    assert!(!range.is_user_accessible());
+}
+
+#[test_case]
+fn dump_ranges_are_sorted_and_coalesced() {
+    use rust_os::memory::dump::{self, MappedRange};
+
+    let mut previous: Option<MappedRange> = None;
+    dump::for_each_active_range(|range| {
+        assert!(range.size > 0);
+        if let Some(previous) = previous {
+            let previous_end = previous.virt_start.as_u64() + previous.size;
+            assert!(previous_end <= range.virt_start.as_u64());
+            // adjacent ranges would have been merged if they could.
+            let contiguous = previous_end == range.virt_start.as_u64()
+                && previous.phys_end() == range.phys_start;
+            assert!(
+                !contiguous
+                    || previous.flags != range.flags
+                    || previous.page_size != range.page_size
+            );
+        }
+        previous = Some(*range);
+    });
+    dump::dump_active();
+}
+
+#[test_case]
+fn dump_reports_huge_pages() {
+    use rust_os::memory::dump;
+
+    let page: Page<Size2MiB> = Page::containing_address(VirtAddr::new(0x5556_0000_0000));
+    let frame = {
+        let mut guard = PAGING.lock();
+        let paging = guard.as_mut().unwrap();
+        let frame: PhysFrame<Size2MiB> = paging.frame_allocator.allocate_frame().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            memory::map_2mib_page(
+                page,
+                frame,
+                flags,
+                &mut paging.mapper,
+                &mut paging.frame_allocator,
+            )
+            .expect("map_2mib_page failed");
+        }
+        frame
+    };
+
+    let mut found = None;
+    dump::for_each_active_range(|range| {
+        if range.virt_start == page.start_address() {
+            found = Some(*range);
+        }
+    });
+    let range = found.expect("huge page not reported");
+    assert_eq!(range.page_size, MappedPageSize::Size2MiB);
+    assert_eq!(range.phys_start, frame.start_address());
+    assert!(!range.is_writable());
+    assert!(!range.is_executable());
+}
@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::symbols;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn known_function() -> u64 {
+    core::hint::spin_loop();
+    42
+}
+
+#[test_case]
+fn table_was_embedded() {
+    // the runner embeds the table, see tools/embed_symbols.py.
+    assert!(symbols::is_available());
+    assert!(symbols::count() > 0);
+}
+
+#[test_case]
+fn symbols_are_sorted() {
+    let mut previous = symbols::get(0).unwrap().address;
+    for index in 1..symbols::count() {
+        let symbol = symbols::get(index).unwrap();
+        assert!(previous < symbol.address);
+        previous = symbol.address;
+    }
+}
+
+#[test_case]
+fn lookup_finds_functions() {
+    assert_eq!(known_function(), 42);
+    let addr = VirtAddr::new(known_function as *const () as u64);
+    let (symbol, offset) = symbols::lookup(addr).unwrap();
+    assert!(symbol.name.ends_with("known_function"));
+    assert_eq!(offset, 0);
+
+    let (inner, offset) = symbols::lookup(addr + 1u64).unwrap();
+    assert_eq!(inner, symbol);
+    assert_eq!(offset, 1);
+}
+
+#[test_case]
+fn lookup_ignores_other_addresses() {
+    assert_eq!(symbols::lookup(VirtAddr::new(0x1000)), None);
+    assert_eq!(symbols::get(symbols::count()), None);
+}
@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use rust_os::time::{self, Deadline, Timestamp};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn timer_runs_at_the_default_frequency() {
+    assert_eq!(time::frequency(), time::DEFAULT_FREQUENCY);
+    let period = time::tick_duration();
+    assert!(period > Duration::from_micros(999) && period < Duration::from_micros(1001));
+    assert_eq!(time::duration_to_ticks(period * 10), 10);
+    assert_eq!(
+        time::duration_to_ticks(period * 10 + Duration::from_nanos(1)),
+        11
+    );
+}
+
+#[test_case]
+fn sleep_waits_for_ticks() {
+    let (ticks, uptime) = (time::ticks(), time::uptime());
+    time::sleep_ticks(10);
+    assert!(time::ticks() >= ticks + 10);
+    assert!(time::uptime() >= uptime + 9 * time::tick_duration());
+
+    let uptime = time::uptime();
+    time::sleep(Duration::from_millis(5));
+    assert!(time::uptime() >= uptime + Duration::from_millis(4));
+}
+
+#[test_case]
+fn uptime_is_monotonic() {
+    let mut last = time::uptime();
+    for _ in 0..1000 {
+        let now = time::uptime();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn busy_wait_works_without_interrupts() {
+    let ticks = time::ticks();
+    interrupts::without_interrupts(|| {
+        time::busy_wait(Duration::from_millis(3));
+        // no timer interrupt could be handled.
+        assert_eq!(time::ticks(), ticks);
+        // falls back to busy waiting instead of halting forever.
+        time::sleep_ticks(2);
+    });
+    // the interrupt that became pending is handled now.
+    time::sleep_ticks(1);
+    assert!(time::ticks() > ticks);
+}
+
+#[test_case]
+fn deadlines_expire() {
+    let deadline = Deadline::after(Duration::from_millis(20));
+    assert!(!deadline.has_passed());
+    assert!(deadline.remaining() <= Duration::from_millis(20));
+    while !deadline.has_passed() {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(deadline.remaining(), Duration::from_secs(0));
+}
+
+#[test_case]
+fn huge_durations_saturate() {
+    assert_eq!(time::duration_to_ticks(Duration::MAX), u64::MAX);
+    let deadline = Deadline::after(Duration::MAX);
+    assert!(!deadline.has_passed());
+}
+
+// formats into a fixed buffer, the test kernel has no heap.
+struct Buffer {
+    bytes: [u8; 32],
+    len: usize,
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.bytes
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn timestamps_show_seconds_and_microseconds() {
+    let mut buffer = Buffer {
+        bytes: [0; 32],
+        len: 0,
+    };
+    let timestamp = Timestamp(Duration::new(12, 345_678_901));
+    write!(buffer, "{}", timestamp).unwrap();
+    assert_eq!(&buffer.bytes[..buffer.len], b"[   12.345678]");
+}
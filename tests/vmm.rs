@@ -0,0 +1,188 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::mmio::{self, CacheMode};
+use rust_os::memory::vmm::{self, VirtualRangeAllocator, VmmError};
+use rust_os::memory::{self, BitmapFrameAllocator};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn ranges_do_not_overlap() {
+    let a = vmm::reserve(3 * 4096).unwrap();
+    let b = vmm::reserve(4096).unwrap();
+    assert_eq!(a.size(), 3 * 4096);
+    assert!(a.end() <= b.start() || b.end() <= a.start());
+    unsafe {
+        vmm::release(a);
+        vmm::release(b);
+    }
+}
+
+#[test_case]
+fn allocate_maps_zeroed_memory() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let free_before = free_frames();
+
+    let range = vmm::allocate(2 * 4096, flags).unwrap();
+    assert!(free_frames() <= free_before - 2);
+    let ptr: *mut u64 = range.start().as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+
+    let mapped_free = free_frames();
+    unsafe { vmm::free(range) };
+    assert_eq!(free_frames(), mapped_free + 2);
+}
+
+#[test_case]
+fn allocate_maps_read_only_memory() {
+    // the frame is cleared before the read-only mapping exists.
+    let range = vmm::allocate(4096, PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE).unwrap();
+    let ptr: *const u64 = range.start().as_ptr();
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    unsafe { vmm::free(range) };
+}
+
+#[test_case]
+fn released_ranges_are_merged() {
+    let mut allocator = VirtualRangeAllocator::new(0x1000, 0x5000);
+    let a = allocator.reserve(0x1000, 0x1000).unwrap();
+    let b = allocator.reserve(0x1000, 0x1000).unwrap();
+    let c = allocator.reserve(0x2000, 0x1000).unwrap();
+    assert_eq!(allocator.reserve(0x1000, 0x1000), None);
+
+    allocator.release(a, 0x1000);
+    allocator.release(c, 0x2000);
+    allocator.release(b, 0x1000);
+    assert_eq!(allocator.reserve(0x4000, 0x1000), Some(0x1000));
+}
+
+#[test_case]
+fn full_table_keeps_ranges_it_cannot_split() {
+    // page 0 stays reserved, pages 1 to 3 form one free range and every
+    // second page after them another one, 128 ranges in total.
+    let mut allocator = VirtualRangeAllocator::new(0, 260 * 0x1000);
+    for page in 0..260 {
+        assert_eq!(allocator.reserve(0x1000, 0x1000), Some(page * 0x1000));
+    }
+    allocator.release(0x1000, 0x3000);
+    for page in (6..260).step_by(2) {
+        allocator.release(page * 0x1000, 0x1000);
+    }
+
+    // taking the aligned middle of pages 1 to 3 would need one more entry, the
+    // next range is used instead.
+    assert_eq!(allocator.reserve(0x1000, 0x2000), Some(0x6000));
+    assert_eq!(allocator.reserve(0x3000, 0x1000), Some(0x1000));
+
+    // aligning the start would wrap around the address space.
+    let mut allocator = VirtualRangeAllocator::new(0xffff_ffff_fff0_0000, 0xffff_ffff_ffff_f000);
+    assert_eq!(allocator.reserve(0x1000, 1 << 63), None);
+    assert_eq!(
+        allocator.reserve(0x1000, 0x1000),
+        Some(0xffff_ffff_fff0_0000)
+    );
+}
+
+#[test_case]
+fn ioremap_maps_device_memory_until_dropped() {
+    // the VGA text buffer is device memory that is always there. The physical
+    // memory mapping covers it too, so it has to use the same cache mode.
+    let region = unsafe { mmio::ioremap(PhysAddr::new(0xb8000), 4000, CacheMode::WriteBack) }
+        .expect("ioremap failed");
+    let addr = region.virt_addr();
+    assert_eq!(translate(addr), Some(PhysAddr::new(0xb8000)));
+
+    unsafe {
+        region.write::<u16>(0, 0x0f41);
+        assert_eq!(region.read::<u16>(0), 0x0f41);
+    }
+
+    drop(region);
+    assert_eq!(translate(addr), None);
+}
+
+#[test_case]
+fn ioremap_rejects_empty_ranges() {
+    let result = unsafe { mmio::ioremap(PhysAddr::new(0xb8000), 0, CacheMode::WriteBack) };
+    assert!(matches!(result, Err(VmmError::EmptyRange)));
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    memory::with_kernel_memory(|m| m.mapper.translate_addr(addr)).unwrap()
+}
+
+#[test_case]
+fn lazy_pages_are_mapped_on_access() {
+    use rust_os::memory::lazy;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let (range, handle) = lazy::reserve(16 * 4096, flags).unwrap();
+    let free_before = free_frames();
+
+    let ptr: *mut u64 = (range.start() + 5 * 4096u64).as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    // only the touched page was mapped.
+    let translated = memory::with_kernel_memory(|m| {
+        (
+            m.mapper.translate_addr(range.start()),
+            m.mapper.translate_addr(range.start() + 5 * 4096u64),
+        )
+    })
+    .unwrap();
+    assert!(translated.0.is_none());
+    assert!(translated.1.is_some());
+    assert!(free_frames() < free_before);
+
+    lazy::unregister(handle);
+    unsafe { vmm::free(range) };
+}
+
+#[test_case]
+fn read_only_lazy_pages_are_mapped_on_read() {
+    use rust_os::memory::lazy;
+
+    let (range, handle) = lazy::reserve(2 * 4096, PageTableFlags::NO_EXECUTE).unwrap();
+    let ptr: *const u64 = (range.start() + 4096u64).as_ptr();
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    assert!(translate(range.start() + 4096u64).is_some());
+
+    lazy::unregister(handle);
+    unsafe { vmm::free(range) };
+}
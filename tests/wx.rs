@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use lazy_static::lazy_static;
+use rust_os::memory::{self, BitmapFrameAllocator};
+use rust_os::{allocator, exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const EXECUTE_HEAP: u8 = 1;
+const WRITE_TEXT: u8 = 2;
+
+static STAGE: AtomicU8 = AtomicU8::new(0);
+// the address the current stage is expected to fault on.
+static EXPECTED_ADDR: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::gdt::init();
+    init_test_idt();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::wx::protect_kernel(&mut mapper) }.expect("failed to protect kernel segments");
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    serial_print!("wx::heap_is_not_executable...\t");
+    // a single `ret` instruction.
+    let code = Box::new([0xc3u8; 16]);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    EXPECTED_ADDR.store(code.as_ptr() as u64, Ordering::SeqCst);
+    STAGE.store(EXECUTE_HEAP, Ordering::SeqCst);
+    function();
+    if STAGE.load(Ordering::SeqCst) != 0 {
+        serial_println!("[no page fault]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    serial_println!("[ok]");
+
+    serial_print!("wx::text_is_not_writable...\t");
+    let text = text_target as *const () as *mut u8;
+    EXPECTED_ADDR.store(text as u64, Ordering::SeqCst);
+    STAGE.store(WRITE_TEXT, Ordering::SeqCst);
+    unsafe { text.write_volatile(0xc3) };
+
+    serial_println!("[no page fault]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn text_target() {}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let violation = match STAGE.load(Ordering::SeqCst) {
+        EXECUTE_HEAP => PageFaultErrorCode::INSTRUCTION_FETCH,
+        WRITE_TEXT => PageFaultErrorCode::CAUSED_BY_WRITE,
+        _ => PageFaultErrorCode::empty(),
+    };
+    let expected = violation | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if violation.is_empty()
+        || !error_code.contains(expected)
+        || Cr2::read().as_u64() != EXPECTED_ADDR.load(Ordering::SeqCst)
+    {
+        serial_println!("[failed]");
+        serial_println!(
+            "unexpected page fault at {:?}: {:?}",
+            Cr2::read(),
+            error_code
+        );
+        exit_qemu(QemuExitCode::Failed);
+        loop {}
+    }
+
+    if violation == PageFaultErrorCode::CAUSED_BY_WRITE {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        loop {}
+    }
+    // the heap function was entered with a call, so resuming at the return
+    // address on the stack returns from it.
+    STAGE.store(0, Ordering::SeqCst);
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            let return_addr = frame.stack_pointer.as_ptr::<u64>().read();
+            frame.instruction_pointer = VirtAddr::new(return_addr);
+            frame.stack_pointer += 8u64;
+        });
+    }
+}
@@ -0,0 +1,168 @@
+#!/usr/bin/env python3
+"""Writes the function symbols of a kernel ELF file into its `.ksyms` section.
+
+The kernel reserves the section (see `src/symbols.rs`) and reads the table at
+runtime to print `function+offset` for addresses. Used as the cargo runner:
+
+    embed_symbols.py [--then COMMAND... --] KERNEL [ARGS...]
+
+patches KERNEL in place and then runs `COMMAND KERNEL ARGS`, so that
+`bootimage runner` builds the boot image from the patched file.
+
+Table layout (little endian):
+
+    magic       4 bytes  b"KSYM"
+    count       u32      number of entries
+    strings     u32      offset of the string table from the table start
+    strings_len u32
+    entries     count * (address u64, size u32, name offset u32), sorted by address
+    string table, names are NUL terminated
+"""
+
+import re
+import struct
+import subprocess
+import sys
+
+SECTION = ".ksyms"
+MAGIC = b"KSYM"
+HEADER = struct.Struct("<4sIII")
+ENTRY = struct.Struct("<QII")
+
+SHT_PROGBITS = 1
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+HASH = re.compile(r"^h[0-9a-f]{16}$")
+ESCAPES = {
+    "SP": "@",
+    "BP": "*",
+    "RF": "&",
+    "LT": "<",
+    "GT": ">",
+    "LP": "(",
+    "RP": ")",
+    "C": ",",
+}
+
+
+def demangle(name):
+    """Demangles legacy Rust symbol names, other names are returned as they are."""
+    if not (name.startswith("_ZN") and name.endswith("E")):
+        return name
+    rest = name[3:-1]
+    parts = []
+    while rest:
+        match = re.match(r"^(\d+)", rest)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        parts.append(rest[start : start + length])
+        rest = rest[start + length :]
+    if parts and HASH.match(parts[-1]):
+        parts.pop()
+    return "::".join(unescape(part) for part in parts)
+
+
+def unescape(part):
+    if part.startswith("_$"):
+        part = part[1:]
+
+    def replace(match):
+        code = match.group(1)
+        if code.startswith("u"):
+            return chr(int(code[1:], 16))
+        return ESCAPES.get(code, match.group(0))
+
+    part = re.sub(r"\$([A-Za-z0-9]+)\$", replace, part)
+    return part.replace("..", "::")
+
+
+def read_sections(elf):
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit("embed_symbols: not an ELF64 file")
+    shoff = struct.unpack_from("<Q", elf, 0x28)[0]
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    sections = []
+    for i in range(shnum):
+        (name, kind, _flags, addr, offset, size, link, _info, _align, entsize) = struct.unpack_from(
+            "<IIQQQQIIQQ", elf, shoff + i * shentsize
+        )
+        sections.append(
+            {"name": name, "type": kind, "addr": addr, "offset": offset,
+             "size": size, "link": link, "entsize": entsize}
+        )
+    names = sections[shstrndx]
+    for section in sections:
+        section["name"] = c_string(elf, names["offset"] + section["name"])
+    return sections
+
+
+def c_string(data, offset):
+    end = data.index(b"\0", offset)
+    return data[offset:end].decode("utf-8", "replace")
+
+
+def function_symbols(elf, sections):
+    symbols = {}
+    for symtab in (s for s in sections if s["type"] == SHT_SYMTAB):
+        strtab = sections[symtab["link"]]
+        for offset in range(symtab["offset"], symtab["offset"] + symtab["size"], symtab["entsize"]):
+            name, info, _other, _shndx, value, size = struct.unpack_from("<IBBHQQ", elf, offset)
+            if info & 0xF != STT_FUNC or value == 0 or size == 0:
+                continue
+            symbols[value] = (size, demangle(c_string(elf, strtab["offset"] + name)))
+    return sorted((addr, size, name) for addr, (size, name) in symbols.items())
+
+
+def build_table(symbols):
+    strings = bytearray()
+    entries = bytearray()
+    offsets = {}
+    for addr, size, name in symbols:
+        if name not in offsets:
+            offsets[name] = len(strings)
+            strings += name.encode() + b"\0"
+        entries += ENTRY.pack(addr, min(size, 0xFFFF_FFFF), offsets[name])
+    strings_offset = HEADER.size + len(entries)
+    header = HEADER.pack(MAGIC, len(symbols), strings_offset, len(strings))
+    return header + entries + strings
+
+
+def embed(path):
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+    sections = read_sections(elf)
+    target = next((s for s in sections if s["name"] == SECTION), None)
+    if target is None or target["type"] != SHT_PROGBITS:
+        sys.exit("embed_symbols: {} has no {} section".format(path, SECTION))
+
+    table = build_table(function_symbols(elf, sections))
+    if len(table) > target["size"]:
+        sys.exit(
+            "embed_symbols: symbol table needs {} bytes but {} only has {}, "
+            "increase SYMBOL_TABLE_SIZE in src/symbols.rs".format(len(table), SECTION, target["size"])
+        )
+    start = target["offset"]
+    elf[start : start + target["size"]] = table + bytes(target["size"] - len(table))
+    with open(path, "wb") as f:
+        f.write(elf)
+
+
+def main(args):
+    command = []
+    if args and args[0] == "--then":
+        if "--" not in args:
+            sys.exit(__doc__)
+        end = args.index("--")
+        command, args = args[1:end], args[end + 1 :]
+    if not args:
+        sys.exit(__doc__)
+    embed(args[0])
+    if command:
+        sys.exit(subprocess.call(command + args))
+
+
+if __name__ == "__main__":
+    main(sys.argv[1:])